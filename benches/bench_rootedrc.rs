@@ -1,5 +1,5 @@
 use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
-use objgraph::{rc::RootedRc, Root};
+use objgraph::{biased::BiasedRootedRc, rc::RootedRc, Root};
 use std::{rc::Rc, sync::Arc};
 
 #[inline(never)]
@@ -8,6 +8,18 @@ fn rootedrc_clone_and_drop(root: &Root, x: RootedRc<()>) {
     x.safely_drop(root);
 }
 
+#[inline(never)]
+fn biasedrootedrc_clone_and_drop(root: &Root, x: BiasedRootedRc<()>) {
+    x.clone(root).safely_drop(root);
+    x.safely_drop(root);
+}
+
+#[inline(never)]
+fn biasedrootedrc_clone_unrooted_and_drop(root: &Root, x: BiasedRootedRc<()>) {
+    drop(x.clone_unrooted());
+    x.safely_drop(root);
+}
+
 #[inline(never)]
 fn arc_clone_and_drop(x: Arc<()>) {
     #[allow(clippy::redundant_clone)]
@@ -34,6 +46,28 @@ fn criterion_benchmark(c: &mut Criterion) {
                 BatchSize::SmallInput,
             );
         });
+        group.bench_function("BiasedRootedRc", |b| {
+            b.iter_batched(
+                || {
+                    let root = Root::new();
+                    let rc = BiasedRootedRc::new(&root, ());
+                    (root, rc)
+                },
+                |(root, rc)| biasedrootedrc_clone_and_drop(&root, rc),
+                BatchSize::SmallInput,
+            );
+        });
+        group.bench_function("BiasedRootedRc unrooted", |b| {
+            b.iter_batched(
+                || {
+                    let root = Root::new();
+                    let rc = BiasedRootedRc::new(&root, ());
+                    (root, rc)
+                },
+                |(root, rc)| biasedrootedrc_clone_unrooted_and_drop(&root, rc),
+                BatchSize::SmallInput,
+            );
+        });
         group.bench_function("Arc", |b| {
             b.iter_batched(|| Arc::new(()), arc_clone_and_drop, BatchSize::SmallInput);
         });
@@ -42,6 +76,32 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     }
 
+    {
+        // Clones of a long-lived object, so that the last reference is never
+        // dropped. This is the fast path that `BiasedRootedRc` shares with
+        // `RootedRc`.
+        let mut group = c.benchmark_group("clone and drop long-lived");
+        let root = Root::new();
+        let rc = RootedRc::new(&root, ());
+        group.bench_function("RootedRc", |b| {
+            b.iter(|| rc.clone(&root).safely_drop(&root));
+        });
+        rc.safely_drop(&root);
+        let rc = BiasedRootedRc::new(&root, ());
+        group.bench_function("BiasedRootedRc", |b| {
+            b.iter(|| rc.clone(&root).safely_drop(&root));
+        });
+        group.bench_function("BiasedRootedRc unrooted", |b| {
+            b.iter(|| drop(rc.clone_unrooted()));
+        });
+        rc.safely_drop(&root);
+        let arc = Arc::new(());
+        group.bench_function("Arc", |b| {
+            #[allow(clippy::redundant_clone)]
+            b.iter(|| drop(arc.clone()));
+        });
+    }
+
     {
         let mut group = c.benchmark_group("cross-core clone");
         const N: usize = 10000;
@@ -122,6 +182,60 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     }
 
+    {
+        // Scans a large, sparsely populated descriptor table, summing the
+        // open descriptors. `RootedRc` is a single pointer with a niche, so
+        // `Option<RootedRc<_>>` is one word. `TwoWordHandle` stands in for
+        // the previous layout, which also copied the tag into every handle
+        // and had no niche, making each table entry three words.
+        #[allow(dead_code)]
+        struct TwoWordHandle {
+            tag: u64,
+            ptr: *const u64,
+        }
+
+        const N: usize = 1 << 20;
+        let root = Root::new();
+        let objects: Vec<RootedRc<u64>> =
+            (0..N / 4).map(|i| RootedRc::new(&root, i as u64)).collect();
+        let table: Vec<Option<RootedRc<u64>>> = (0..N)
+            .map(|i| (i % 4 == 0).then(|| objects[i / 4].clone(&root)))
+            .collect();
+        let two_word_table: Vec<Option<TwoWordHandle>> = (0..N)
+            .map(|i| {
+                (i % 4 == 0).then(|| TwoWordHandle {
+                    tag: 0,
+                    ptr: RootedRc::as_ptr(&objects[i / 4]),
+                })
+            })
+            .collect();
+
+        let mut group = c.benchmark_group("descriptor table scan");
+        group.bench_function("Option<RootedRc>", |b| {
+            b.iter(|| {
+                table
+                    .iter()
+                    .flatten()
+                    .map(|rc| **black_box(rc))
+                    .sum::<u64>()
+            });
+        });
+        group.bench_function("Option<TwoWordHandle>", |b| {
+            b.iter(|| {
+                two_word_table
+                    .iter()
+                    .flatten()
+                    .map(|h| unsafe { *black_box(h).ptr })
+                    .sum::<u64>()
+            });
+        });
+        drop(group);
+
+        for rc in table.into_iter().flatten().chain(objects) {
+            rc.safely_drop(&root);
+        }
+    }
+
     /*
     {
         let _lock = root.lock();
@@ -4,11 +4,31 @@ use atomic_refcell::AtomicRefCell;
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use objgraph::{refcell::RootedRefCell, Root};
 
+#[inline(never)]
+fn rootedrefcell_borrow(root: &Root, x: &RootedRefCell<i32>) -> i32 {
+    *x.borrow(root)
+}
+
+#[inline(never)]
+fn atomicrefcell_borrow(x: &AtomicRefCell<i32>) -> i32 {
+    *x.borrow()
+}
+
+#[inline(never)]
+fn refcell_borrow(x: &RefCell<i32>) -> i32 {
+    *x.borrow()
+}
+
 #[inline(never)]
 fn rootedrefcell_borrow_mut(root: &Root, x: &RootedRefCell<i32>) {
     *x.borrow_mut(root) += 1;
 }
 
+#[inline(never)]
+fn rootedrefcell_get_mut_with(root: &mut Root, x: &RootedRefCell<i32>) {
+    *x.get_mut_with(root) += 1;
+}
+
 #[inline(never)]
 fn mutex_borrow_mut(x: &Mutex<i32>) {
     *x.lock().unwrap() += 1;
@@ -30,6 +50,34 @@ fn refcell_borrow_mut(x: &RefCell<i32>) {
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
+    {
+        let mut group = c.benchmark_group("borrow");
+        group.bench_function("RootedRefCell", |b| {
+            b.iter_batched_ref(
+                || {
+                    let root = Root::new();
+                    let x = RootedRefCell::new(&root, 0);
+                    (root, x)
+                },
+                |(root, x)| rootedrefcell_borrow(root, x),
+                BatchSize::SmallInput,
+            );
+        });
+        group.bench_function("AtomicRefCell", |b| {
+            b.iter_batched_ref(
+                || AtomicRefCell::new(0),
+                |x| atomicrefcell_borrow(x),
+                BatchSize::SmallInput,
+            );
+        });
+        group.bench_function("RefCell", |b| {
+            b.iter_batched_ref(
+                || RefCell::new(0),
+                |x| refcell_borrow(x),
+                BatchSize::SmallInput,
+            );
+        });
+    }
     {
         let mut group = c.benchmark_group("borrow_mut");
         group.bench_function("RootedRefCell", |b| {
@@ -43,6 +91,17 @@ fn criterion_benchmark(c: &mut Criterion) {
                 BatchSize::SmallInput,
             );
         });
+        group.bench_function("RootedRefCell get_mut_with", |b| {
+            b.iter_batched_ref(
+                || {
+                    let root = Root::new();
+                    let x = RootedRefCell::new(&root, 0);
+                    (root, x)
+                },
+                |(root, x)| rootedrefcell_get_mut_with(root, x),
+                BatchSize::SmallInput,
+            );
+        });
         group.bench_function("Mutex", |b| {
             b.iter_batched_ref(
                 || Mutex::new(0),
@@ -1,5 +1,5 @@
-/// Prototyping / examples for how this crate may be used in the
-/// [shadow](https://github.com/shadow/shadow) simulator.
+//! Prototyping / examples for how this crate may be used in the
+//! [shadow](https://github.com/shadow/shadow) simulator.
 
 mod v1 {
     use objgraph::{refcell::RootedRefCell, Root};
@@ -0,0 +1,14 @@
+//! Drives the same state machine as `tests/proptest_model.rs` with
+//! fuzzer-generated operation sequences. Run with `cargo fuzz run ops`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../tests/model/mod.rs"]
+mod model;
+
+fuzz_target!(|ops: Vec<model::Op>| {
+    // libfuzzer treats any panic as a crash, even if it's caught, so skip the
+    // operations that are expected to panic.
+    model::run(&ops, false);
+});
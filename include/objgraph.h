@@ -0,0 +1,148 @@
+#ifndef OBJGRAPH_H
+#define OBJGRAPH_H
+
+/* Generated by cbindgen from src/ffi.rs. Do not edit manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of a C API call.
+typedef enum ObjgraphStatus {
+  ObjgraphStatus_Ok = 0,
+  // A required pointer argument was `NULL`.
+  ObjgraphStatus_NullPointer,
+  // The root isn't the one the object is associated with.
+  ObjgraphStatus_WrongRoot,
+  // The object is already mutably borrowed.
+  ObjgraphStatus_MutablyBorrowed,
+  // The object is already immutably borrowed.
+  ObjgraphStatus_Borrowed,
+  // Tried to release a borrow that isn't outstanding.
+  ObjgraphStatus_NotBorrowed,
+  // The root has an active checkpoint, so the object can't be mutably
+  // borrowed.
+  ObjgraphStatus_CheckpointActive,
+  // A panic happened while the object was mutably borrowed.
+  ObjgraphStatus_Poisoned,
+  // The root still has objects that need it to be released, so it wasn't
+  // freed. Only returned with the `leak_check` feature.
+  ObjgraphStatus_LiveObjects,
+} ObjgraphStatus;
+
+// Opaque handle owning one reference to a rooted object.
+typedef struct ObjgraphObject ObjgraphObject;
+
+// Root of an "object graph". Locking a `Root` allows inexpensive access
+// to associated `RootedRc`s and `RootedRefCell`s.
+typedef struct ObjgraphRoot ObjgraphRoot;
+
+// Destructor callback for the data wrapped by a `ForeignObject`. May be
+// `NULL`.
+typedef void (*ObjgraphDestructor)(void *data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Creates a new root. Must be freed with `objgraph_root_free`.
+struct ObjgraphRoot *objgraph_root_new(void);
+
+// Frees a root created by `objgraph_root_new`. Freeing `NULL` does nothing.
+//
+// With the `leak_check` feature, fails with `LiveObjects` if any objects
+// still need the root to be released, leaving the root valid so that they
+// can be.
+//
+// # Safety
+//
+// `root` must have been returned by `objgraph_root_new` (or be `NULL`), and
+// must not be used again once freed.
+enum ObjgraphStatus objgraph_root_free(struct ObjgraphRoot *root);
+
+// Creates a new object associated with `root`, wrapping `data`. `destructor`
+// (which may be `NULL`) is called on `data` when the last reference is
+// released. Writes the new handle to `*out`.
+//
+// # Safety
+//
+// `root` must be a valid root that is only accessed by the current thread for
+// the duration of the call. `out` must be valid for writes. See
+// `ForeignObject::new` for requirements on `data` and `destructor`.
+enum ObjgraphStatus objgraph_object_new(const struct ObjgraphRoot *root,
+                                        void *data,
+                                        ObjgraphDestructor destructor,
+                                        struct ObjgraphObject **out);
+
+// Creates a new handle referencing the same object as `obj`, and writes it to
+// `*out`.
+//
+// # Safety
+//
+// `root` and `obj` must be valid, and only accessed by the current thread for
+// the duration of the call. `out` must be valid for writes.
+enum ObjgraphStatus objgraph_object_ref(const struct ObjgraphRoot *root,
+                                        const struct ObjgraphObject *obj,
+                                        struct ObjgraphObject **out);
+
+// Releases the reference owned by `obj`. On success `obj` must not be used
+// again. If this was the last reference, the object's destructor is called.
+//
+// On failure `obj` is left untouched.
+//
+// # Safety
+//
+// `root` and `obj` must be valid, and only accessed by the current thread for
+// the duration of the call.
+enum ObjgraphStatus objgraph_object_unref(const struct ObjgraphRoot *root,
+                                          struct ObjgraphObject *obj);
+
+// Immutably borrows the object, writing its data pointer to `*out`. The
+// borrow must later be released with `objgraph_object_release`, using the
+// same root.
+//
+// # Safety
+//
+// `root` and `obj` must be valid, and only accessed by the current thread for
+// the duration of the call. `out` must be valid for writes. The root must not
+// be freed or passed to another thread while the borrow is outstanding.
+enum ObjgraphStatus objgraph_object_borrow(const struct ObjgraphRoot *root,
+                                           const struct ObjgraphObject *obj,
+                                           const void **out);
+
+// Mutably borrows the object, writing its data pointer to `*out`. The borrow
+// must later be released with `objgraph_object_release_mut`, using the same
+// root.
+//
+// # Safety
+//
+// As for `objgraph_object_borrow`.
+enum ObjgraphStatus objgraph_object_borrow_mut(const struct ObjgraphRoot *root,
+                                               const struct ObjgraphObject *obj,
+                                               void **out);
+
+// Releases a borrow acquired with `objgraph_object_borrow`.
+//
+// # Safety
+//
+// `root` and `obj` must be valid, and only accessed by the current thread for
+// the duration of the call. The borrow being released must have been acquired
+// by C through `objgraph_object_borrow`, and the data pointer it returned must
+// not be used again.
+enum ObjgraphStatus objgraph_object_release(const struct ObjgraphRoot *root,
+                                            const struct ObjgraphObject *obj);
+
+// Releases a borrow acquired with `objgraph_object_borrow_mut`.
+//
+// # Safety
+//
+// As for `objgraph_object_release`, but for `objgraph_object_borrow_mut`.
+enum ObjgraphStatus objgraph_object_release_mut(const struct ObjgraphRoot *root,
+                                                const struct ObjgraphObject *obj);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* OBJGRAPH_H */
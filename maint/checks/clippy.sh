@@ -2,4 +2,8 @@
 
 set -euxo pipefail
 
-cargo clippy --all-features --all-targets -- -D warnings
\ No newline at end of file
+# Every feature except `nightly`, which needs a nightly compiler.
+FEATURES=atomic,ffi,journal,leak_check,leak_check_types,poison,stats,tag128,tracing
+
+cargo clippy --features "$FEATURES" --all-targets -- -D warnings
+cargo +nightly clippy --all-features --all-targets -- -D warnings
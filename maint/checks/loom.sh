@@ -0,0 +1,6 @@
+#!/bin/bash
+
+set -euxo pipefail
+
+RUSTFLAGS="--cfg loom" cargo test --release --test loom
+RUSTFLAGS="--cfg loom" cargo test --release --lib test_loom
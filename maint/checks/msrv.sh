@@ -0,0 +1,11 @@
+#!/bin/bash
+
+set -euxo pipefail
+
+# The library's minimum supported Rust version, as declared by `rust-version`
+# in Cargo.toml. Tests and benches aren't built, since their dev-dependencies
+# need a newer compiler.
+FEATURES=atomic,ffi,journal,leak_check,leak_check_types,poison,stats,tag128,tracing
+
+rustup toolchain install 1.81 --profile minimal
+cargo +1.81 build --features "$FEATURES"
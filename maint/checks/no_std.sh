@@ -0,0 +1,7 @@
+#!/bin/bash
+
+set -euxo pipefail
+
+# A target without `std`, to check that nothing slipped in from it.
+rustup target add x86_64-unknown-none
+cargo build --no-default-features --features stats,ffi,journal,leak_check,leak_check_types,tracing --target x86_64-unknown-none
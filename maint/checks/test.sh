@@ -3,4 +3,6 @@
 set -euxo pipefail
 
 RUST_BACKTRACE=1 cargo test
+RUST_BACKTRACE=1 cargo test --features atomic,ffi,journal,leak_check,leak_check_types,poison,stats,tag128,tracing
+RUST_BACKTRACE=1 cargo +nightly test --all-features
 RUST_BACKTRACE=1 cargo test --examples
\ No newline at end of file
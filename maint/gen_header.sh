@@ -0,0 +1,8 @@
+#!/bin/bash
+
+# Regenerates the C header for the `ffi` module.
+# Requires cbindgen: `cargo install cbindgen`.
+
+set -euxo pipefail
+
+cbindgen --quiet --config cbindgen.toml --crate objgraph --output include/objgraph.h
@@ -0,0 +1,345 @@
+//! Biased reference counting: a `RootedRc` variant that can also be cloned by
+//! threads that don't hold the root.
+//!
+//! `BiasedRootedRc` is cloned and dropped with the root held, using a
+//! non-atomic count, exactly like `RootedRc`. Occasionally another thread,
+//! such as a logging thread, needs a short-lived reference without taking the
+//! root. `BiasedRootedRc::clone_unrooted` provides one as an `UnrootedRc`,
+//! which is counted separately with an atomic count and can be dropped
+//! anywhere.
+//!
+//! All of the rooted references together hold a single reference in the
+//! atomic count. When the last of them is dropped, that reference is released,
+//! merging the two counts; whichever count reaches zero last frees the value.
+//!
+//! With the `stats` feature, rooted operations are counted as `RootedRc`
+//! operations, and the object counts as live until its last rooted reference
+//! is released.
+
+use alloc::boxed::Box;
+use core::ptr::NonNull;
+
+use crate::sync::{fence, refcount_overflow, AtomicU32, Count, Ordering, MAX_REFCOUNT};
+use crate::{Root, RootId, RootProof, Tag};
+
+struct BiasedInternal<T> {
+    // Tag of the associated root, kept here rather than in each handle, as
+    // for `RootedRc`. Immutable.
+    tag: Tag,
+    val: T,
+    // Number of `BiasedRootedRc`s. Only accessed with the root held.
+    rooted_count: Count,
+    // Number of `UnrootedRc`s, plus one shared by all of the `BiasedRootedRc`s
+    // while any exist.
+    unrooted_count: AtomicU32,
+}
+
+impl<T> BiasedInternal<T> {
+    /// Releases a reference from the atomic count, freeing the block if it
+    /// was the last.
+    ///
+    /// # Safety
+    ///
+    /// `this` must point to a live block, and the reference must not be used
+    /// again.
+    unsafe fn release_unrooted(this: *mut Self) {
+        // SAFETY: Guaranteed by caller.
+        let count = unsafe { &(*this).unrooted_count };
+        // As in `Arc::drop`.
+        if count.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        fence(Ordering::Acquire);
+        // SAFETY: No references remain.
+        drop(unsafe { Box::from_raw(this) });
+        trace_event!(ptr = ?this, "freed BiasedRootedRc");
+    }
+
+    /// Adds a reference to the atomic count, which must already be nonzero.
+    fn acquire_unrooted(&self) {
+        // As in `Arc::clone`.
+        let old = self.unrooted_count.fetch_add(1, Ordering::Relaxed);
+        if old >= MAX_REFCOUNT {
+            refcount_overflow();
+        }
+    }
+}
+
+#[track_caller]
+fn check_root(root: &Root, tag: Tag) {
+    if root.tag != tag {
+        error_event!(root = ?root.tag, expected = ?tag, "used BiasedRootedRc with wrong root");
+        panic!("Tried using a lock for {:?} instead of {:?}", root.tag, tag);
+    }
+}
+
+/// Like `RootedRc`, but also allowing references to be taken without the
+/// root, via `clone_unrooted`.
+///
+/// Operations that take the root cost the same as `RootedRc`'s. As for
+/// `RootedRc`, instances must be destroyed using `safely_drop`. Like
+/// `RootedRc`, a handle is a single pointer.
+pub struct BiasedRootedRc<T> {
+    internal: NonNull<BiasedInternal<T>>,
+}
+
+impl<T> BiasedRootedRc<T> {
+    /// Creates a new object associated with `root`.
+    pub fn new(root: &Root, val: T) -> Self {
+        let internal = NonNull::from(Box::leak(Box::new(BiasedInternal {
+            tag: root.tag(),
+            val,
+            rooted_count: Count::new(1),
+            unrooted_count: AtomicU32::new(1),
+        })));
+        root.live
+            .add(internal.as_ptr(), core::any::type_name::<T>());
+        root.stats.rc_alloc();
+        trace_event!(
+            tag = ?root.tag(),
+            ptr = ?internal,
+            ty = core::any::type_name::<T>(),
+            "allocated BiasedRootedRc"
+        );
+        Self { internal }
+    }
+
+    fn internal(&self) -> &BiasedInternal<T> {
+        // SAFETY: The block is live as long as any reference is.
+        unsafe { self.internal.as_ref() }
+    }
+
+    fn tag(&self) -> Tag {
+        self.internal().tag
+    }
+
+    /// Like `RootedRc::root_id`.
+    pub fn root_id(this: &Self) -> RootId {
+        RootId(this.tag())
+    }
+
+    /// Like `RootedRc::belongs_to`.
+    pub fn belongs_to(this: &Self, root: &Root) -> bool {
+        root.tag == this.tag()
+    }
+
+    /// Like `RootedRc::clone`. Panics if `root` is for the wrong `Root`.
+    pub fn clone<P: RootProof + ?Sized>(&self, root: &P) -> Self {
+        let root = root.root();
+        check_root(root, self.tag());
+        // We hold the root, so no other thread is accessing the count.
+        self.internal().rooted_count.inc();
+        root.stats.rc_clone();
+        Self {
+            internal: self.internal,
+        }
+    }
+
+    /// Creates a reference that doesn't need the root to use or drop. Costs
+    /// an atomic increment, and a later atomic decrement.
+    pub fn clone_unrooted(&self) -> UnrootedRc<T> {
+        // There's at least one rooted reference (`self`), so the count is
+        // already nonzero.
+        self.internal().acquire_unrooted();
+        UnrootedRc {
+            internal: self.internal,
+        }
+    }
+
+    /// Like `RootedRc::safely_drop`. The value is dropped if no other rooted
+    /// or unrooted references remain. Panics if `root` is for the wrong
+    /// `Root`.
+    pub fn safely_drop<P: RootProof + ?Sized>(self, root: &P) {
+        let root = root.root();
+        check_root(root, self.tag());
+        let last_rooted = self.internal().rooted_count.dec() == 0;
+        root.stats.rc_safe_drop(last_rooted);
+        if last_rooted {
+            // The last rooted reference; release the reference they shared.
+            root.live.remove(self.internal.as_ptr());
+            // SAFETY: The block is live, and `self` isn't used again.
+            unsafe { BiasedInternal::release_unrooted(self.internal.as_ptr()) };
+        }
+        // The reference has been released.
+        core::mem::forget(self);
+    }
+}
+
+impl<T> Drop for BiasedRootedRc<T> {
+    fn drop(&mut self) {
+        // Only reached if `safely_drop` wasn't called.
+        log::error!("Dropped without calling `safely_drop`");
+        error_event!(
+            tag = ?self.tag(),
+            ptr = ?self.internal,
+            ty = core::any::type_name::<T>(),
+            "leaked BiasedRootedRc dropped without calling `safely_drop`"
+        );
+
+        // As for `RootedRc`, the block is simply leaked.
+        #[cfg(debug_assertions)]
+        if !crate::panicking() {
+            panic!("Dropped without calling `safely_drop`");
+        }
+    }
+}
+
+impl<T> core::ops::Deref for BiasedRootedRc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.internal().val
+    }
+}
+
+// SAFETY: As for `RootedRc`. The rooted count is only accessed with the root
+// held, and the unrooted count is atomic. Since an `UnrootedRc` can be the
+// last reference, the value may be dropped on any thread.
+unsafe impl<T: Sync + Send> Send for BiasedRootedRc<T> {}
+unsafe impl<T: Sync + Send> Sync for BiasedRootedRc<T> {}
+
+/// A reference to a `BiasedRootedRc`'s value, taken without the root. Like
+/// `Arc`, it can be cloned and dropped anywhere, using atomic operations.
+pub struct UnrootedRc<T> {
+    internal: NonNull<BiasedInternal<T>>,
+}
+
+impl<T> Clone for UnrootedRc<T> {
+    fn clone(&self) -> Self {
+        // SAFETY: The block is live as long as `self` is.
+        unsafe { self.internal.as_ref() }.acquire_unrooted();
+        Self {
+            internal: self.internal,
+        }
+    }
+}
+
+impl<T> Drop for UnrootedRc<T> {
+    fn drop(&mut self) {
+        // SAFETY: The block is live, and `self` isn't used again.
+        unsafe { BiasedInternal::release_unrooted(self.internal.as_ptr()) };
+    }
+}
+
+impl<T> core::ops::Deref for UnrootedRc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The block is live as long as `self` is.
+        &unsafe { self.internal.as_ref() }.val
+    }
+}
+
+// SAFETY: As for `Arc`.
+unsafe impl<T: Sync + Send> Send for UnrootedRc<T> {}
+unsafe impl<T: Sync + Send> Sync for UnrootedRc<T> {}
+
+#[cfg(test)]
+mod test_biased_rc {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    struct SetOnDrop(Arc<AtomicBool>);
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn rooted_clone_and_drop() {
+        let root = Root::new();
+        let rc = BiasedRootedRc::new(&root, 1);
+        let rc2 = rc.clone(&root);
+        assert_eq!(*rc2, 1);
+        rc.safely_drop(&root);
+        rc2.safely_drop(&root);
+    }
+
+    #[test]
+    fn unrooted_reference_outlives_rooted() {
+        let root = Root::new();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let rc = BiasedRootedRc::new(&root, SetOnDrop(dropped.clone()));
+        let unrooted = rc.clone_unrooted();
+        rc.safely_drop(&root);
+        assert!(!dropped.load(Ordering::Relaxed));
+
+        // The last reference is dropped on another thread, without the root.
+        thread::spawn(move || drop(unrooted)).join().unwrap();
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn rooted_reference_outlives_unrooted() {
+        let root = Root::new();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let rc = BiasedRootedRc::new(&root, SetOnDrop(dropped.clone()));
+        let unrooted = rc.clone_unrooted();
+        drop(unrooted.clone());
+        drop(unrooted);
+        assert!(!dropped.load(Ordering::Relaxed));
+        rc.safely_drop(&root);
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn belongs_to_root() {
+        let root = Root::new();
+        let other = Root::new();
+        let rc = BiasedRootedRc::new(&root, 0);
+        assert_eq!(BiasedRootedRc::root_id(&rc), root.id());
+        assert!(BiasedRootedRc::belongs_to(&rc, &root));
+        assert!(!BiasedRootedRc::belongs_to(&rc, &other));
+        rc.safely_drop(&root);
+    }
+
+    #[test]
+    #[should_panic(expected = "Tried using a lock for")]
+    fn clone_with_wrong_root_panics() {
+        let root = Root::new();
+        let other = Root::new();
+        let rc = BiasedRootedRc::new(&root, 0);
+        let _ = rc.clone(&other);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn counts_rooted_operations() {
+        let root = Root::new();
+        let rc = BiasedRootedRc::new(&root, 0);
+        let rc2 = rc.clone(&root);
+        let unrooted = rc.clone_unrooted();
+        rc.safely_drop(&root);
+        assert_eq!(root.stats().live_rcs, 1);
+        rc2.safely_drop(&root);
+
+        let stats = root.stats();
+        assert_eq!(stats.rc_allocs, 1);
+        assert_eq!(stats.rc_clones, 1);
+        assert_eq!(stats.rc_safe_drops, 2);
+        // Unrooted references don't need the root.
+        assert_eq!(stats.live_rcs, 0);
+        drop(unrooted);
+    }
+
+    #[test]
+    fn handles_are_pointer_sized() {
+        use core::mem::size_of;
+
+        assert_eq!(size_of::<BiasedRootedRc<u64>>(), size_of::<usize>());
+        assert_eq!(size_of::<Option<BiasedRootedRc<u64>>>(), size_of::<usize>());
+        assert_eq!(size_of::<Option<UnrootedRc<u64>>>(), size_of::<usize>());
+    }
+
+    #[test]
+    #[should_panic]
+    fn drop_without_lock_panics() {
+        let root = Root::new();
+        let _ = BiasedRootedRc::new(&root, 0);
+    }
+}
@@ -0,0 +1,385 @@
+//! Deep cloning of object graphs from one `Root` into another.
+//!
+//! This is intended for modelling `fork()`: duplicating everything reachable
+//! from a process into a fresh root. Every `RootedRc` allocation reachable
+//! from the cloned value is copied exactly once, so sharing within the graph is
+//! preserved in the copy, and every copied object is associated with the
+//! destination root.
+//!
+//! `RootedWeak` references are copied as weak references to the copy of
+//! their allocation, so cycles closed by weak references (e.g. an object
+//! created with `RootedRc::new_cyclic` that refers to itself) are preserved
+//! too. Cycles of strong references can't be cloned.
+//!
+//! See `Root::deep_clone_into`.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use allocator_api2::alloc::Allocator;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use crate::rc::{RootedRc, RootedWeak};
+use crate::Root;
+
+/// Types that can be cloned into a different `Root`.
+///
+/// Implementations should call `deep_clone` on any rooted objects they
+/// contain, passing along `ctx`, rather than cloning them directly.
+pub trait DeepClone: Sized {
+    fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self;
+}
+
+/// Copy of a shared allocation. `weak` refers to the copy from the time its
+/// block is allocated, before its value has been cloned. `strong` keeps the
+/// copy alive once it's complete, until the whole graph has been cloned, so
+/// that later references to the same allocation can reuse it.
+struct Cloned<T, A: Allocator> {
+    weak: RootedWeak<T, A>,
+    strong: Option<RootedRc<T, A>>,
+}
+
+trait ClonedRc {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn safely_drop(self: Box<Self>, root: &Root);
+}
+
+impl<T: 'static, A: Allocator + 'static> ClonedRc for Cloned<T, A> {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn safely_drop(self: Box<Self>, root: &Root) {
+        if let Some(strong) = self.strong {
+            strong.safely_drop(root);
+        }
+        self.weak.safely_drop(root);
+    }
+}
+
+/// State for a single deep clone operation. Values cloned with the same
+/// context share their copies of any allocations they have in common.
+pub struct DeepCloneContext<'a> {
+    src: &'a Root,
+    dst: &'a Root,
+    // Maps the address of each source allocation to its copy.
+    shared: BTreeMap<*const (), Box<dyn ClonedRc>>,
+}
+
+impl<'a> DeepCloneContext<'a> {
+    /// Creates a context for cloning objects associated with `src` into `dst`.
+    pub fn new(src: &'a Root, dst: &'a Root) -> Self {
+        Self {
+            src,
+            dst,
+            shared: BTreeMap::new(),
+        }
+    }
+
+    /// The root that objects are being cloned from.
+    pub fn src_root(&self) -> &'a Root {
+        self.src
+    }
+
+    /// The root that objects are being cloned into.
+    pub fn dst_root(&self) -> &'a Root {
+        self.dst
+    }
+
+    fn cloned<T: 'static, A: Allocator + 'static>(
+        &mut self,
+        key: *const (),
+    ) -> Option<&mut Cloned<T, A>> {
+        let cloned = self.shared.get_mut(&key)?;
+        Some(cloned.as_any_mut().downcast_mut::<Cloned<T, A>>().unwrap())
+    }
+
+    /// A weak reference to the copy of the allocation at `key`, if it has
+    /// been reached before. The copy may still be under construction.
+    pub(crate) fn copy_of<T: 'static, A: Allocator + 'static>(
+        &mut self,
+        key: *const (),
+    ) -> Option<RootedWeak<T, A>> {
+        let dst = self.dst;
+        Some(self.cloned::<T, A>(key)?.weak.clone(dst))
+    }
+
+    /// Records that `weak` refers to the copy of the allocation at `key`,
+    /// whose value may not have been cloned yet.
+    pub(crate) fn start_copy<T: 'static, A: Allocator + 'static>(
+        &mut self,
+        key: *const (),
+        weak: RootedWeak<T, A>,
+    ) {
+        let cloned = Cloned { weak, strong: None };
+        assert!(self.shared.insert(key, Box::new(cloned)).is_none());
+    }
+
+    /// Records that the copy of the allocation at `key` is complete, keeping
+    /// it alive until the context is dropped.
+    pub(crate) fn finish_copy<T: 'static, A: Allocator + 'static>(
+        &mut self,
+        key: *const (),
+        strong: RootedRc<T, A>,
+    ) {
+        let cloned = self.cloned::<T, A>(key).unwrap();
+        assert!(cloned.strong.is_none());
+        cloned.strong = Some(strong);
+    }
+}
+
+impl<'a> Drop for DeepCloneContext<'a> {
+    fn drop(&mut self) {
+        for copy in core::mem::take(&mut self.shared).into_values() {
+            copy.safely_drop(self.dst);
+        }
+    }
+}
+
+macro_rules! impl_deep_clone_via_clone {
+    ($($t:ty),*) => {
+        $(
+            impl DeepClone for $t {
+                fn deep_clone(&self, _ctx: &mut DeepCloneContext) -> Self {
+                    self.clone()
+                }
+            }
+        )*
+    };
+}
+impl_deep_clone_via_clone!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    String
+);
+
+impl<T: DeepClone> DeepClone for Option<T> {
+    fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+        self.as_ref().map(|v| v.deep_clone(ctx))
+    }
+}
+
+impl<T: DeepClone> DeepClone for Box<T> {
+    fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+        Box::new((**self).deep_clone(ctx))
+    }
+}
+
+impl<T: DeepClone> DeepClone for Vec<T> {
+    fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+        self.iter().map(|v| v.deep_clone(ctx)).collect()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K, V> DeepClone for HashMap<K, V>
+where
+    K: Clone + Eq + core::hash::Hash,
+    V: DeepClone,
+{
+    fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+        self.iter()
+            .map(|(k, v)| (k.clone(), v.deep_clone(ctx)))
+            .collect()
+    }
+}
+
+impl<K, V> DeepClone for BTreeMap<K, V>
+where
+    K: Clone + Ord,
+    V: DeepClone,
+{
+    fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+        self.iter()
+            .map(|(k, v)| (k.clone(), v.deep_clone(ctx)))
+            .collect()
+    }
+}
+
+macro_rules! impl_deep_clone_for_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: DeepClone),*> DeepClone for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+                let ($($name,)*) = self;
+                ($($name.deep_clone(ctx),)*)
+            }
+        }
+    };
+}
+impl_deep_clone_for_tuple!(A);
+impl_deep_clone_for_tuple!(A, B);
+impl_deep_clone_for_tuple!(A, B, C);
+impl_deep_clone_for_tuple!(A, B, C, D);
+
+#[cfg(test)]
+mod test_deep_clone {
+    use super::*;
+
+    use crate::refcell::RootedRefCell;
+
+    type Descriptor = RootedRc<RootedRefCell<bool>>;
+
+    struct Process {
+        descriptors: Vec<Descriptor>,
+    }
+
+    impl DeepClone for Process {
+        fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+            Self {
+                descriptors: self.descriptors.deep_clone(ctx),
+            }
+        }
+    }
+
+    impl Process {
+        fn safely_drop(self, root: &Root) {
+            for d in self.descriptors {
+                d.safely_drop(root);
+            }
+        }
+    }
+
+    #[test]
+    fn preserves_sharing() {
+        let src = Root::new();
+        let descriptor = RootedRc::new(&src, RootedRefCell::new(&src, true));
+        let process = Process {
+            descriptors: vec![descriptor.clone(&src), descriptor.clone(&src)],
+        };
+        descriptor.safely_drop(&src);
+
+        let dst = Root::new();
+        let forked = src.deep_clone_into(&dst, &process);
+
+        // Both copied descriptors refer to the same (new) allocation...
+        *forked.descriptors[0].borrow_mut(&dst) = false;
+        assert!(!*forked.descriptors[1].borrow(&dst));
+        // ...which is distinct from the original.
+        assert!(*process.descriptors[0].borrow(&src));
+
+        forked.safely_drop(&dst);
+        process.safely_drop(&src);
+    }
+
+    #[test]
+    fn shares_across_values_in_one_context() {
+        let src = Root::new();
+        let a = RootedRc::new(&src, 1);
+        let b = a.clone(&src);
+        let pair = (a, b);
+
+        let dst = Root::new();
+        let (a2, b2) = src.deep_clone_into(&dst, &pair);
+        assert!(std::ptr::eq(&*a2, &*b2));
+        a2.safely_drop(&dst);
+        b2.safely_drop(&dst);
+
+        let (a, b) = pair;
+        a.safely_drop(&src);
+        b.safely_drop(&src);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected")]
+    fn copies_belong_to_destination_root() {
+        let src = Root::new();
+        let cell = RootedRefCell::new(&src, 0);
+        let dst = Root::new();
+        let copy = src.deep_clone_into(&dst, &cell);
+        let _ = copy.borrow(&src);
+    }
+
+    #[test]
+    fn weak_self_reference_is_preserved() {
+        struct Process {
+            this: RootedRefCell<Option<RootedWeak<Process>>>,
+            pid: u32,
+        }
+        impl DeepClone for Process {
+            fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+                Process {
+                    this: self.this.deep_clone(ctx),
+                    pid: self.pid,
+                }
+            }
+        }
+
+        let src = Root::new();
+        let process = RootedRc::new_cyclic(&src, |weak| Process {
+            this: RootedRefCell::new(&src, Some(weak.clone(&src))),
+            pid: 1,
+        });
+        // A weak reference to an object that's already gone is copied as one
+        // that can't be upgraded either.
+        let gone = RootedRc::new(
+            &src,
+            Process {
+                this: RootedRefCell::new(&src, None),
+                pid: 2,
+            },
+        );
+        let gone_weak = RootedRc::downgrade(&gone, &src);
+        gone.safely_drop(&src);
+
+        let dst = Root::new();
+        let graph = (process.clone(&src), gone_weak);
+        let (fork, gone_copy) = src.deep_clone_into(&dst, &graph);
+        graph.0.safely_drop(&src);
+        graph.1.safely_drop(&src);
+        {
+            let this = fork.this.borrow(&dst);
+            let upgraded = this.as_ref().unwrap().upgrade(&dst).unwrap();
+            assert!(core::ptr::eq(&*upgraded, &*fork));
+            assert!(!core::ptr::eq(&*upgraded, &*process));
+            assert_eq!(upgraded.pid, 1);
+            upgraded.safely_drop(&dst);
+        }
+        assert!(gone_copy.upgrade(&dst).is_none());
+        gone_copy.safely_drop(&dst);
+
+        for (rc, root) in [(process, &src), (fork, &dst)] {
+            let weak = rc.this.borrow_mut(root).take().unwrap();
+            weak.safely_drop(root);
+            rc.safely_drop(root);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "reference cycle")]
+    fn cycle_panics() {
+        struct Node(RootedRefCell<Option<RootedRc<Node>>>);
+        impl DeepClone for Node {
+            fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+                Node(self.0.deep_clone(ctx))
+            }
+        }
+
+        let src = Root::new();
+        let a = RootedRc::new(&src, Node(RootedRefCell::new(&src, None)));
+        let a2 = a.clone(&src);
+        *a.0.borrow_mut(&src) = Some(a2);
+
+        let dst = Root::new();
+        let _ = src.deep_clone_into(&dst, &a);
+    }
+}
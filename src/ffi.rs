@@ -0,0 +1,482 @@
+//! C API, enabled by the `ffi` feature.
+//!
+//! This is intended to support incrementally porting C code to Rust: C modules
+//! can hold references to objects owned by Rust, and Rust code can hold
+//! references to objects created from C.
+//!
+//! Objects are type-erased `RootedRc<RootedRefCell<ForeignObject>>`s. Each
+//! `ObjgraphObject` handle owns one reference; `objgraph_object_ref` creates a
+//! new handle and `objgraph_object_unref` releases one. The wrapped data is
+//! destroyed via its destructor callback when the last reference is released.
+//!
+//! Unlike the Rust API, none of these functions panic on misuse. Using the
+//! wrong root, borrowing an object that is already borrowed, passing a
+//! `NULL` pointer, or (with the `leak_check` feature) freeing a root that
+//! objects still need results in an `ObjgraphStatus` error code instead.
+//!
+//! The corresponding C header is `include/objgraph.h`, generated with
+//! `cbindgen` (see `cbindgen.toml` and `maint/gen_header.sh`).
+
+use alloc::boxed::Box;
+use core::ffi::c_void;
+
+use crate::rc::RootedRc;
+use crate::refcell::{BorrowError, RootedRefCell};
+use crate::Root;
+
+/// Result of a C API call.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ObjgraphStatus {
+    Ok = 0,
+    /// A required pointer argument was `NULL`.
+    NullPointer,
+    /// The root isn't the one the object is associated with.
+    WrongRoot,
+    /// The object is already mutably borrowed.
+    MutablyBorrowed,
+    /// The object is already immutably borrowed.
+    Borrowed,
+    /// Tried to release a borrow that isn't outstanding.
+    NotBorrowed,
+    /// The root has an active checkpoint, so the object can't be mutably
+    /// borrowed.
+    CheckpointActive,
+    /// A panic happened while the object was mutably borrowed.
+    Poisoned,
+    /// The root still has objects that need it to be released, so it wasn't
+    /// freed. Only returned with the `leak_check` feature.
+    LiveObjects,
+}
+
+impl From<BorrowError> for ObjgraphStatus {
+    fn from(e: BorrowError) -> Self {
+        match e {
+            BorrowError::WrongRoot => ObjgraphStatus::WrongRoot,
+            BorrowError::MutablyBorrowed => ObjgraphStatus::MutablyBorrowed,
+            BorrowError::Borrowed => ObjgraphStatus::Borrowed,
+            BorrowError::CheckpointActive => ObjgraphStatus::CheckpointActive,
+            BorrowError::Poisoned => ObjgraphStatus::Poisoned,
+        }
+    }
+}
+
+/// Destructor callback for the data wrapped by a `ForeignObject`. May be
+/// `NULL`.
+pub type ObjgraphDestructor = Option<unsafe extern "C" fn(data: *mut c_void)>;
+
+/// Type-erased data, wrapped in a rooted object shared between C and Rust.
+pub struct ForeignObject {
+    data: *mut c_void,
+    destructor: ObjgraphDestructor,
+}
+
+impl ForeignObject {
+    /// # Safety
+    ///
+    /// `data` must be safe to access from whichever thread currently holds the
+    /// associated `Root`, and `destructor` (if any) must be safe to call on
+    /// `data` exactly once.
+    pub unsafe fn new(data: *mut c_void, destructor: ObjgraphDestructor) -> Self {
+        Self { data, destructor }
+    }
+
+    /// Wraps a Rust object, which will be dropped with the `ForeignObject`.
+    pub fn from_box<T: Send>(val: Box<T>) -> Self {
+        unsafe extern "C" fn drop_box<T>(data: *mut c_void) {
+            // SAFETY: `data` came from `Box::into_raw` below.
+            drop(unsafe { Box::from_raw(data as *mut T) });
+        }
+        // SAFETY: `T` is `Send`, and `drop_box` is called at most once.
+        unsafe { Self::new(Box::into_raw(val) as *mut c_void, Some(drop_box::<T>)) }
+    }
+
+    /// The wrapped data pointer.
+    pub fn data(&self) -> *mut c_void {
+        self.data
+    }
+}
+
+impl Drop for ForeignObject {
+    fn drop(&mut self) {
+        if let Some(destructor) = self.destructor {
+            // SAFETY: Guaranteed by the contract of `ForeignObject::new`.
+            unsafe { destructor(self.data) }
+        }
+    }
+}
+
+// SAFETY: Guaranteed by the contract of `ForeignObject::new`.
+unsafe impl Send for ForeignObject {}
+
+/// Opaque handle owning one reference to a rooted object.
+pub struct ObjgraphObject(RootedRc<RootedRefCell<ForeignObject>>);
+
+impl ObjgraphObject {
+    /// Transfers ownership of `rc` to a handle that can be passed to C.
+    pub fn into_raw(rc: RootedRc<RootedRefCell<ForeignObject>>) -> *mut ObjgraphObject {
+        Box::into_raw(Box::new(ObjgraphObject(rc)))
+    }
+
+    /// Takes back ownership of a reference from a handle created by C or by
+    /// `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `obj` must be a valid handle, and must not be used again.
+    pub unsafe fn from_raw(obj: *mut ObjgraphObject) -> RootedRc<RootedRefCell<ForeignObject>> {
+        // SAFETY: Caller guarantees `obj` is a valid, owned handle.
+        unsafe { Box::from_raw(obj) }.0
+    }
+}
+
+/// Creates a new root. Must be freed with `objgraph_root_free`.
+#[no_mangle]
+pub extern "C" fn objgraph_root_new() -> *mut Root {
+    Box::into_raw(Box::new(Root::new()))
+}
+
+/// Frees a root created by `objgraph_root_new`. Freeing `NULL` does nothing.
+///
+/// With the `leak_check` feature, fails with `LiveObjects` if any objects
+/// still need the root to be released, leaving the root valid so that they
+/// can be.
+///
+/// # Safety
+///
+/// `root` must have been returned by `objgraph_root_new` (or be `NULL`), and
+/// must not be used again once freed.
+#[no_mangle]
+pub unsafe extern "C" fn objgraph_root_free(root: *mut Root) -> ObjgraphStatus {
+    if root.is_null() {
+        return ObjgraphStatus::Ok;
+    }
+    // Checked before taking ownership, since dropping a root with live
+    // objects panics in debug builds, and a panic can't unwind into C.
+    // SAFETY: Guaranteed by caller.
+    #[cfg(feature = "leak_check")]
+    if unsafe { &*root }.live.count() != 0 {
+        return ObjgraphStatus::LiveObjects;
+    }
+    // SAFETY: Guaranteed by caller.
+    drop(unsafe { Box::from_raw(root) });
+    ObjgraphStatus::Ok
+}
+
+/// Creates a new object associated with `root`, wrapping `data`. `destructor`
+/// (which may be `NULL`) is called on `data` when the last reference is
+/// released. Writes the new handle to `*out`.
+///
+/// # Safety
+///
+/// `root` must be a valid root that is only accessed by the current thread for
+/// the duration of the call. `out` must be valid for writes. See
+/// `ForeignObject::new` for requirements on `data` and `destructor`.
+#[no_mangle]
+pub unsafe extern "C" fn objgraph_object_new(
+    root: *const Root,
+    data: *mut c_void,
+    destructor: ObjgraphDestructor,
+    out: *mut *mut ObjgraphObject,
+) -> ObjgraphStatus {
+    // SAFETY: Guaranteed by caller.
+    let Some(root) = (unsafe { root.as_ref() }) else {
+        return ObjgraphStatus::NullPointer;
+    };
+    if out.is_null() {
+        return ObjgraphStatus::NullPointer;
+    }
+    // SAFETY: Guaranteed by caller.
+    let val = unsafe { ForeignObject::new(data, destructor) };
+    let rc = RootedRc::new(root, RootedRefCell::new(root, val));
+    // SAFETY: Checked for NULL above; caller guarantees validity.
+    unsafe { out.write(ObjgraphObject::into_raw(rc)) };
+    ObjgraphStatus::Ok
+}
+
+/// Creates a new handle referencing the same object as `obj`, and writes it to
+/// `*out`.
+///
+/// # Safety
+///
+/// `root` and `obj` must be valid, and only accessed by the current thread for
+/// the duration of the call. `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn objgraph_object_ref(
+    root: *const Root,
+    obj: *const ObjgraphObject,
+    out: *mut *mut ObjgraphObject,
+) -> ObjgraphStatus {
+    // SAFETY: Guaranteed by caller.
+    let (Some(root), Some(obj)) = (unsafe { root.as_ref() }, unsafe { obj.as_ref() }) else {
+        return ObjgraphStatus::NullPointer;
+    };
+    if out.is_null() {
+        return ObjgraphStatus::NullPointer;
+    }
+    if obj.0.tag() != root.tag() {
+        return ObjgraphStatus::WrongRoot;
+    }
+    let rc = obj.0.clone(root);
+    // SAFETY: Checked for NULL above; caller guarantees validity.
+    unsafe { out.write(ObjgraphObject::into_raw(rc)) };
+    ObjgraphStatus::Ok
+}
+
+/// Releases the reference owned by `obj`. On success `obj` must not be used
+/// again. If this was the last reference, the object's destructor is called.
+///
+/// On failure `obj` is left untouched.
+///
+/// # Safety
+///
+/// `root` and `obj` must be valid, and only accessed by the current thread for
+/// the duration of the call.
+#[no_mangle]
+pub unsafe extern "C" fn objgraph_object_unref(
+    root: *const Root,
+    obj: *mut ObjgraphObject,
+) -> ObjgraphStatus {
+    // SAFETY: Guaranteed by caller.
+    let (Some(root), Some(handle)) = (unsafe { root.as_ref() }, unsafe { obj.as_ref() }) else {
+        return ObjgraphStatus::NullPointer;
+    };
+    if handle.0.tag() != root.tag() {
+        return ObjgraphStatus::WrongRoot;
+    }
+    // SAFETY: Guaranteed by caller.
+    unsafe { ObjgraphObject::from_raw(obj) }.safely_drop(root);
+    ObjgraphStatus::Ok
+}
+
+/// Immutably borrows the object, writing its data pointer to `*out`. The
+/// borrow must later be released with `objgraph_object_release`, using the
+/// same root.
+///
+/// # Safety
+///
+/// `root` and `obj` must be valid, and only accessed by the current thread for
+/// the duration of the call. `out` must be valid for writes. The root must not
+/// be freed or passed to another thread while the borrow is outstanding.
+#[no_mangle]
+pub unsafe extern "C" fn objgraph_object_borrow(
+    root: *const Root,
+    obj: *const ObjgraphObject,
+    out: *mut *const c_void,
+) -> ObjgraphStatus {
+    // SAFETY: Guaranteed by caller.
+    let (Some(root), Some(obj)) = (unsafe { root.as_ref() }, unsafe { obj.as_ref() }) else {
+        return ObjgraphStatus::NullPointer;
+    };
+    if out.is_null() {
+        return ObjgraphStatus::NullPointer;
+    }
+    match obj.0.try_borrow(root) {
+        Ok(guard) => {
+            // SAFETY: Checked for NULL above; caller guarantees validity.
+            unsafe { out.write(guard.data()) };
+            core::mem::forget(guard);
+            ObjgraphStatus::Ok
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Mutably borrows the object, writing its data pointer to `*out`. The borrow
+/// must later be released with `objgraph_object_release_mut`, using the same
+/// root.
+///
+/// # Safety
+///
+/// As for `objgraph_object_borrow`.
+#[no_mangle]
+pub unsafe extern "C" fn objgraph_object_borrow_mut(
+    root: *const Root,
+    obj: *const ObjgraphObject,
+    out: *mut *mut c_void,
+) -> ObjgraphStatus {
+    // SAFETY: Guaranteed by caller.
+    let (Some(root), Some(obj)) = (unsafe { root.as_ref() }, unsafe { obj.as_ref() }) else {
+        return ObjgraphStatus::NullPointer;
+    };
+    if out.is_null() {
+        return ObjgraphStatus::NullPointer;
+    }
+    match obj.0.try_borrow_mut(root) {
+        Ok(guard) => {
+            // SAFETY: Checked for NULL above; caller guarantees validity.
+            unsafe { out.write(guard.data()) };
+            core::mem::forget(guard);
+            ObjgraphStatus::Ok
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Releases a borrow acquired with `objgraph_object_borrow`.
+///
+/// # Safety
+///
+/// `root` and `obj` must be valid, and only accessed by the current thread for
+/// the duration of the call. The borrow being released must have been acquired
+/// by C through `objgraph_object_borrow`, and the data pointer it returned must
+/// not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn objgraph_object_release(
+    root: *const Root,
+    obj: *const ObjgraphObject,
+) -> ObjgraphStatus {
+    // SAFETY: Guaranteed by caller.
+    let (Some(root), Some(obj)) = (unsafe { root.as_ref() }, unsafe { obj.as_ref() }) else {
+        return ObjgraphStatus::NullPointer;
+    };
+    if obj.0.tag() != root.tag() {
+        return ObjgraphStatus::WrongRoot;
+    }
+    // SAFETY: Guaranteed by caller.
+    match unsafe { obj.0.unleak_borrow(root) } {
+        Some(guard) => {
+            drop(guard);
+            ObjgraphStatus::Ok
+        }
+        None => ObjgraphStatus::NotBorrowed,
+    }
+}
+
+/// Releases a borrow acquired with `objgraph_object_borrow_mut`.
+///
+/// # Safety
+///
+/// As for `objgraph_object_release`, but for `objgraph_object_borrow_mut`.
+#[no_mangle]
+pub unsafe extern "C" fn objgraph_object_release_mut(
+    root: *const Root,
+    obj: *const ObjgraphObject,
+) -> ObjgraphStatus {
+    // SAFETY: Guaranteed by caller.
+    let (Some(root), Some(obj)) = (unsafe { root.as_ref() }, unsafe { obj.as_ref() }) else {
+        return ObjgraphStatus::NullPointer;
+    };
+    if obj.0.tag() != root.tag() {
+        return ObjgraphStatus::WrongRoot;
+    }
+    // SAFETY: Guaranteed by caller.
+    match unsafe { obj.0.unleak_borrow_mut(root) } {
+        Some(guard) => {
+            drop(guard);
+            ObjgraphStatus::Ok
+        }
+        None => ObjgraphStatus::NotBorrowed,
+    }
+}
+
+#[cfg(test)]
+mod test_ffi {
+    use std::ptr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    static DESTROYED: AtomicU32 = AtomicU32::new(0);
+
+    unsafe extern "C" fn count_destroyed(data: *mut c_void) {
+        drop(unsafe { Box::from_raw(data as *mut u32) });
+        DESTROYED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn ref_borrow_unref() {
+        let root = objgraph_root_new();
+        let other_root = objgraph_root_new();
+        let data = Box::into_raw(Box::new(7u32)) as *mut c_void;
+        let mut obj = ptr::null_mut();
+        let mut obj2 = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                objgraph_object_new(root, data, Some(count_destroyed), &mut obj),
+                ObjgraphStatus::Ok
+            );
+            assert_eq!(
+                objgraph_object_ref(other_root, obj, &mut obj2),
+                ObjgraphStatus::WrongRoot
+            );
+            assert_eq!(
+                objgraph_object_ref(root, obj, &mut obj2),
+                ObjgraphStatus::Ok
+            );
+
+            let mut p = ptr::null_mut();
+            assert_eq!(
+                objgraph_object_borrow_mut(root, obj, &mut p),
+                ObjgraphStatus::Ok
+            );
+            *(p as *mut u32) += 1;
+            let mut q = ptr::null();
+            assert_eq!(
+                objgraph_object_borrow(root, obj2, &mut q),
+                ObjgraphStatus::MutablyBorrowed
+            );
+            assert_eq!(
+                objgraph_object_release(root, obj),
+                ObjgraphStatus::NotBorrowed
+            );
+            assert_eq!(objgraph_object_release_mut(root, obj), ObjgraphStatus::Ok);
+
+            assert_eq!(
+                objgraph_object_borrow(root, obj2, &mut q),
+                ObjgraphStatus::Ok
+            );
+            assert_eq!(*(q as *const u32), 8);
+            assert_eq!(objgraph_object_release(root, obj2), ObjgraphStatus::Ok);
+
+            assert_eq!(
+                objgraph_object_unref(other_root, obj),
+                ObjgraphStatus::WrongRoot
+            );
+            assert_eq!(objgraph_object_unref(root, obj), ObjgraphStatus::Ok);
+            assert_eq!(DESTROYED.load(Ordering::Relaxed), 0);
+            assert_eq!(objgraph_object_unref(root, obj2), ObjgraphStatus::Ok);
+            assert_eq!(DESTROYED.load(Ordering::Relaxed), 1);
+
+            assert_eq!(objgraph_root_free(other_root), ObjgraphStatus::Ok);
+            assert_eq!(objgraph_root_free(root), ObjgraphStatus::Ok);
+        }
+    }
+
+    #[cfg(feature = "leak_check")]
+    #[test]
+    fn free_root_with_live_objects_fails() {
+        unsafe {
+            let root = objgraph_root_new();
+            let mut obj = core::ptr::null_mut();
+            assert_eq!(
+                objgraph_object_new(root, core::ptr::null_mut(), None, &mut obj),
+                ObjgraphStatus::Ok
+            );
+            assert_eq!(objgraph_root_free(root), ObjgraphStatus::LiveObjects);
+            assert_eq!(objgraph_object_unref(root, obj), ObjgraphStatus::Ok);
+            assert_eq!(objgraph_root_free(root), ObjgraphStatus::Ok);
+        }
+    }
+
+    #[test]
+    fn rust_object_to_c_and_back() {
+        let root = Root::new();
+        let rc = RootedRc::new(
+            &root,
+            RootedRefCell::new(&root, ForeignObject::from_box(Box::new(3i64))),
+        );
+        let handle = ObjgraphObject::into_raw(rc.clone(&root));
+        let mut p = ptr::null();
+        unsafe {
+            assert_eq!(
+                objgraph_object_borrow(&root, handle, &mut p),
+                ObjgraphStatus::Ok
+            );
+            assert_eq!(*(p as *const i64), 3);
+            assert_eq!(objgraph_object_release(&root, handle), ObjgraphStatus::Ok);
+            ObjgraphObject::from_raw(handle).safely_drop(&root);
+        }
+        rc.safely_drop(&root);
+    }
+}
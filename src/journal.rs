@@ -0,0 +1,300 @@
+//! Checkpointing and rollback of a root's mutable state, enabled by the
+//! `journal` feature.
+//!
+//! All mutation of rooted objects goes through a root-proved guard, so the
+//! root is a natural place to keep an undo journal. While a checkpoint is
+//! active, `RootedRc::borrow_mut_journaled` records the value of the cell at
+//! the time it was borrowed, along with a strong reference that keeps the cell
+//! alive. `Root::rollback` replays those records in reverse.
+//!
+//! Only mutations made through `borrow_mut_journaled` can be recorded, so
+//! while a checkpoint is active, other mutable borrows of rooted cells fail
+//! with `BorrowError::CheckpointActive`. Objects created after a checkpoint
+//! are not destroyed by rolling back to it.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use crate::rc::RootedRc;
+use crate::refcell::{BorrowError, RootedRefCell, RootedRefCellRefMut};
+use crate::{Root, RootProof};
+
+/// Identifies a checkpoint created by `Root::checkpoint`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct CheckpointId(u64);
+
+/// A recorded mutation, which can be undone or discarded.
+trait UndoEntry: Send {
+    fn undo(self: Box<Self>, root: &Root);
+    fn discard(self: Box<Self>, root: &Root);
+}
+
+struct Undo<T> {
+    cell: RootedRc<RootedRefCell<T>>,
+    old: T,
+}
+
+impl<T: Send> UndoEntry for Undo<T> {
+    fn undo(self: Box<Self>, root: &Root) {
+        *self.cell.borrow_mut_for_journal(root) = self.old;
+        self.cell.safely_drop(root);
+    }
+
+    fn discard(self: Box<Self>, root: &Root) {
+        self.cell.safely_drop(root);
+    }
+}
+
+#[derive(Default)]
+struct JournalInner {
+    entries: Vec<Box<dyn UndoEntry>>,
+    // Active checkpoints, oldest first, with the journal length at the time
+    // each was created.
+    checkpoints: Vec<(CheckpointId, usize)>,
+    next_id: u64,
+}
+
+/// Undo journal embedded in each `Root`.
+#[derive(Default)]
+pub(crate) struct Journal {
+    inner: RefCell<JournalInner>,
+}
+
+impl Journal {
+    fn is_active(&self) -> bool {
+        !self.inner.borrow().checkpoints.is_empty()
+    }
+
+    /// Fails if a checkpoint is active, since a mutation that isn't recorded
+    /// couldn't be rolled back.
+    pub fn check_unjournaled(&self) -> Result<(), BorrowError> {
+        if self.is_active() {
+            error_event!("unjournaled mutable borrow while a checkpoint is active");
+            return Err(BorrowError::CheckpointActive);
+        }
+        Ok(())
+    }
+
+    pub fn checkpoint(&self) -> CheckpointId {
+        let mut inner = self.inner.borrow_mut();
+        let id = CheckpointId(inner.next_id);
+        inner.next_id += 1;
+        let len = inner.entries.len();
+        inner.checkpoints.push((id, len));
+        id
+    }
+
+    /// Removes checkpoint `id` and all later ones, returning the journal
+    /// length at the time `id` was created.
+    fn remove_checkpoint(inner: &mut JournalInner, id: CheckpointId) -> usize {
+        let Some(pos) = inner.checkpoints.iter().position(|(i, _)| *i == id) else {
+            panic!("Unknown or already released checkpoint {:?}", id);
+        };
+        let (_, len) = inner.checkpoints[pos];
+        inner.checkpoints.truncate(pos);
+        len
+    }
+
+    pub fn rollback(&self, root: &Root, id: CheckpointId) {
+        let entries = {
+            let mut inner = self.inner.borrow_mut();
+            let len = Self::remove_checkpoint(&mut inner, id);
+            inner.entries.split_off(len)
+        };
+        for entry in entries.into_iter().rev() {
+            entry.undo(root);
+        }
+    }
+
+    pub fn release(&self, root: &Root, id: CheckpointId) {
+        let entries = {
+            let mut inner = self.inner.borrow_mut();
+            Self::remove_checkpoint(&mut inner, id);
+            if inner.checkpoints.is_empty() {
+                core::mem::take(&mut inner.entries)
+            } else {
+                // Still needed to roll back to an outer checkpoint.
+                Vec::new()
+            }
+        };
+        for entry in entries {
+            entry.discard(root);
+        }
+    }
+
+    pub fn clear(&self, root: &Root) {
+        let entries = {
+            let mut inner = self.inner.borrow_mut();
+            inner.checkpoints.clear();
+            core::mem::take(&mut inner.entries)
+        };
+        for entry in entries {
+            entry.discard(root);
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> RootedRc<RootedRefCell<T>> {
+    /// Like `RootedRefCell::borrow_mut`, but if `root` has an active
+    /// checkpoint, records the current value so that `Root::rollback` can
+    /// restore it.
+    pub fn borrow_mut_journaled<'a, P: RootProof + ?Sized>(
+        &'a self,
+        // 'a required here for safety, as for `RootedRefCell::borrow`.
+        root: &'a P,
+    ) -> RootedRefCellRefMut<'a, T> {
+        let root = root.root();
+        let guard = self.borrow_mut_for_journal(root);
+        if root.journal.is_active() {
+            let entry = Undo {
+                cell: self.clone(root),
+                old: (*guard).clone(),
+            };
+            root.journal
+                .inner
+                .borrow_mut()
+                .entries
+                .push(Box::new(entry));
+        }
+        guard
+    }
+}
+
+#[cfg(test)]
+mod test_journal {
+    use super::*;
+
+    #[test]
+    fn rollback_restores_values() {
+        let root = Root::new();
+        let a = RootedRc::new(&root, RootedRefCell::new(&root, 1));
+        let b = RootedRc::new(&root, RootedRefCell::new(&root, String::from("x")));
+
+        // Not recorded; no checkpoint is active.
+        *a.borrow_mut_journaled(&root) = 2;
+
+        let checkpoint = root.checkpoint();
+        *a.borrow_mut_journaled(&root) = 3;
+        *a.borrow_mut_journaled(&root) = 4;
+        b.borrow_mut_journaled(&root).push('y');
+        root.rollback(checkpoint);
+
+        assert_eq!(*a.borrow(&root), 2);
+        assert_eq!(*b.borrow(&root), "x");
+
+        a.safely_drop(&root);
+        b.safely_drop(&root);
+    }
+
+    #[test]
+    fn nested_checkpoints() {
+        let root = Root::new();
+        let a = RootedRc::new(&root, RootedRefCell::new(&root, 0));
+
+        let outer = root.checkpoint();
+        *a.borrow_mut_journaled(&root) = 1;
+        let inner = root.checkpoint();
+        *a.borrow_mut_journaled(&root) = 2;
+
+        // Releasing the inner checkpoint keeps its changes, but they can
+        // still be rolled back by the outer one.
+        root.release_checkpoint(inner);
+        assert_eq!(*a.borrow(&root), 2);
+        root.rollback(outer);
+        assert_eq!(*a.borrow(&root), 0);
+
+        a.safely_drop(&root);
+    }
+
+    #[test]
+    fn keeps_cells_alive() {
+        let root = Root::new();
+        let a = RootedRc::new(&root, RootedRefCell::new(&root, 0));
+        let checkpoint = root.checkpoint();
+        *a.borrow_mut_journaled(&root) = 1;
+        a.safely_drop(&root);
+        // Doesn't touch freed memory; the journal holds a reference.
+        root.rollback(checkpoint);
+    }
+
+    #[test]
+    fn unjournaled_borrows_rejected_during_checkpoint() {
+        let mut root = Root::new();
+        let a = RootedRc::new(&root, RootedRefCell::new(&root, 0));
+
+        let checkpoint = root.checkpoint();
+        assert_eq!(
+            a.try_borrow_mut(&root).err(),
+            Some(BorrowError::CheckpointActive)
+        );
+        let err = crate::refcell::borrow_many_mut(&root, [&*a]).err().unwrap();
+        assert_eq!(err.conflicts(), &[(0, BorrowError::CheckpointActive)]);
+        let guard = a.borrow(&root);
+        let guard = crate::refcell::RootedRefCellRef::try_upgrade(guard)
+            .err()
+            .unwrap();
+        drop(guard);
+        *a.borrow_mut_journaled(&root) = 1;
+        root.rollback(checkpoint);
+
+        // Allowed again once no checkpoint is active.
+        *a.borrow_mut(&root) = 2;
+        *a.get_mut_with(&mut root) += 1;
+        assert_eq!(*a.borrow(&root), 3);
+        a.safely_drop(&root);
+    }
+
+    #[test]
+    fn embedded_cells_frozen_during_checkpoint() {
+        // As in the shadow_hierarchy example: the list of processes isn't
+        // `Clone`, so it can't be journaled.
+        struct Host {
+            processes: RootedRefCell<Vec<RootedRc<RootedRefCell<u32>>>>,
+        }
+        let root = Root::new();
+        let host = RootedRc::new(
+            &root,
+            Host {
+                processes: RootedRefCell::new(&root, Vec::new()),
+            },
+        );
+        let process = RootedRc::new(&root, RootedRefCell::new(&root, 1));
+
+        let checkpoint = root.checkpoint();
+        assert_eq!(
+            host.processes.try_borrow_mut(&root).err(),
+            Some(BorrowError::CheckpointActive)
+        );
+        // The processes themselves can still be journaled.
+        *process.borrow_mut_journaled(&root) = 2;
+        root.release_checkpoint(checkpoint);
+
+        host.processes.borrow_mut(&root).push(process);
+        for process in host.processes.borrow_mut(&root).drain(..) {
+            assert_eq!(*process.borrow(&root), 2);
+            process.safely_drop(&root);
+        }
+        host.safely_drop(&root);
+    }
+
+    #[test]
+    #[should_panic(expected = "unjournaled mutable borrow while a checkpoint is active")]
+    fn borrow_mut_during_checkpoint_panics() {
+        let root = Root::new();
+        let a = RootedRc::new(&root, RootedRefCell::new(&root, 0));
+        let _checkpoint = root.checkpoint();
+        // Leaks `a`, but the journal is empty and the root can still be
+        // dropped.
+        *a.borrow_mut(&root) = 1;
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown or already released checkpoint")]
+    fn rollback_twice_panics() {
+        let root = Root::new();
+        let checkpoint = root.checkpoint();
+        root.rollback(checkpoint);
+        root.rollback(checkpoint);
+    }
+}
@@ -1,12 +1,29 @@
 // https://github.com/rust-lang/rfcs/blob/master/text/2585-unsafe-block-in-unsafe-fn.md
 #![deny(unsafe_op_in_unsafe_fn)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
-use std::{
-    marker::PhantomData,
-    sync::atomic::{AtomicU32, Ordering},
-};
+extern crate alloc;
 
-use once_cell::sync::OnceCell;
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+
+use deep_clone::{DeepClone, DeepCloneContext};
+
+#[macro_use]
+mod trace;
+#[cfg(feature = "journal")]
+mod journal;
+mod live;
+mod stats;
+mod sync;
+
+#[cfg(feature = "journal")]
+pub use journal::CheckpointId;
+#[cfg(feature = "stats")]
+pub use stats::RootStats;
+use stats::StatsCell;
+use sync::{AtomicU64, OnceBox, Ordering};
 
 /// Every object root is assigned a Tag, which we ensure is globally unique.
 /// Each Tag value uniquely identifies a Root.
@@ -16,38 +33,157 @@ struct Tag {
     suffix: TagSuffixType,
 }
 
+/// Opaque identifier of a `Root`, e.g. for checking which root an object
+/// belongs to before accessing it, or for keying maps by root. See `Root::id`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RootId(Tag);
+
 /// Larger sizes here reduce the chance of collision, which could lead to
 /// silently missing bugs in some cases. Note though that there would both
 /// have to be a collision, and the code would need to incorrectly try to
 /// access data using the wrong root lock.
 ///
 /// Increasing the size introduces some runtime overhead for storing, copying,
-/// and comparing tag values.
+/// and comparing tag values. The `tag128` feature doubles the size of both the
+/// prefix and the suffix.
+#[cfg(not(feature = "tag128"))]
 type TagPrefixType = u32;
+#[cfg(feature = "tag128")]
+type TagPrefixType = u64;
 
 /// Larger sizes here support a greater number of tags within a given prefix.
 ///
 /// Increasing the size introduces some runtime overhead for storing, copying,
 /// and comparing tag values.
+#[cfg(not(feature = "tag128"))]
 type TagSuffixType = u32;
-type TagSuffixAtomicType = AtomicU32;
+#[cfg(feature = "tag128")]
+type TagSuffixType = u64;
 
-impl Tag {
-    pub fn new() -> Self {
-        // Every instance of this module uses a random prefix for tags.  This is to
-        // handle both the case where this module is used from multiple processes that
-        // share memory, and to handle the case where multiple instances of this module
-        // end up within a single process.
-        static TAG_PREFIX: OnceCell<TagPrefixType> = OnceCell::new();
-        let prefix = *TAG_PREFIX.get_or_init(rand::prelude::random);
+/// Bijective mixing function over prefixes, so that consecutive epochs in
+/// `TagAllocator` get unrelated-looking but never-colliding prefixes.
+#[cfg(not(feature = "tag128"))]
+fn mix_prefix(mut x: TagPrefixType) -> TagPrefixType {
+    // "lowbias32", by Chris Wellons. Each step is invertible.
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+#[cfg(feature = "tag128")]
+fn mix_prefix(mut x: TagPrefixType) -> TagPrefixType {
+    // The splitmix64 finalizer. Each step is invertible.
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    x
+}
+
+/// Hands out unique tags.
+///
+/// Every instance of this module uses a random seed for tag prefixes.  This is
+/// to handle both the case where this module is used from multiple processes
+/// that share memory, and to handle the case where multiple instances of this
+/// module end up within a single process.
+///
+/// Tags are allocated from a single 64-bit counter. The low bits of the counter
+/// are used as the suffix; the remaining bits select an "epoch", and each epoch
+/// gets a fresh prefix. i.e. rather than overflowing after the suffix space is
+/// exhausted, we re-roll the prefix and start again. Since the prefix is a
+/// bijective function of the seed and epoch, tags from one allocator never
+/// collide with each other.
+struct TagAllocator {
+    seed: OnceBox<TagPrefixType>,
+    next: AtomicU64,
+}
 
-        static NEXT_TAG_SUFFIX: TagSuffixAtomicType = TagSuffixAtomicType::new(0);
-        let suffix: TagSuffixType = NEXT_TAG_SUFFIX.fetch_add(1, Ordering::Relaxed);
+/// Source of random bits for seeding tag prefixes. See `set_tag_seed_source`.
+pub type TagSeedSource = fn() -> u64;
+
+#[cfg(not(loom))]
+static TAG_SEED_SOURCE: OnceBox<TagSeedSource> = OnceBox::new();
+// loom's atomics can't be constructed in a const context.
+#[cfg(loom)]
+loom::lazy_static! {
+    static ref TAG_SEED_SOURCE: OnceBox<TagSeedSource> = OnceBox::new();
+}
+
+/// Sets the source of randomness used to seed tag prefixes.
+///
+/// Each instance of this crate that might see another's objects (e.g. in
+/// another process sharing memory) must get a different seed. With the `std`
+/// feature the default source is `rand::random`. Without it there is no
+/// default, and this must be called before the first `Root` is created.
+///
+/// Returns the source back if one has already been set, or if the default has
+/// already been used.
+pub fn set_tag_seed_source(source: TagSeedSource) -> Result<(), TagSeedSource> {
+    TAG_SEED_SOURCE.set(Box::new(source)).map_err(|_| source)
+}
+
+fn tag_seed() -> TagPrefixType {
+    #[cfg(feature = "std")]
+    let source = TAG_SEED_SOURCE.get_or_init(|| Box::new(rand::random::<u64> as TagSeedSource));
+    #[cfg(not(feature = "std"))]
+    let source = TAG_SEED_SOURCE
+        .get()
+        .expect("No tag seed source; call `set_tag_seed_source` before creating a `Root`");
+    source() as TagPrefixType
+}
+
+/// Whether the current thread is unwinding. Without `std` there's no way to
+/// tell, so we assume not.
+#[cfg(debug_assertions)]
+fn panicking() -> bool {
+    #[cfg(feature = "std")]
+    return std::thread::panicking();
+    #[cfg(not(feature = "std"))]
+    return false;
+}
+
+impl TagAllocator {
+    #[cfg(not(loom))]
+    const fn new() -> Self {
+        Self {
+            seed: OnceBox::new(),
+            next: AtomicU64::new(0),
+        }
+    }
+
+    // loom's atomics can't be constructed in a const context.
+    #[cfg(loom)]
+    fn new() -> Self {
+        Self {
+            seed: OnceBox::new(),
+            next: AtomicU64::new(0),
+        }
+    }
 
-        // Detect overflow
-        assert!(suffix != TagSuffixType::MAX);
+    fn next_tag(&self) -> Tag {
+        let seed = *self.seed.get_or_init(|| Box::new(tag_seed()));
+        // Overflowing the 64-bit counter would take centuries at one tag
+        // per nanosecond.
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        let suffix = n as TagSuffixType;
+        let epoch = n.checked_shr(TagSuffixType::BITS).unwrap_or(0);
+        let prefix = mix_prefix(seed ^ epoch as TagPrefixType);
+        Tag { prefix, suffix }
+    }
+}
 
-        Self { prefix, suffix }
+impl Tag {
+    pub fn new() -> Self {
+        #[cfg(not(loom))]
+        static TAG_ALLOCATOR: TagAllocator = TagAllocator::new();
+        #[cfg(loom)]
+        loom::lazy_static! {
+            static ref TAG_ALLOCATOR: TagAllocator = TagAllocator::new();
+        }
+        TAG_ALLOCATOR.next_tag()
     }
 }
 
@@ -55,18 +191,27 @@ impl Tag {
 /// to associated `RootedRc`s and `RootedRefCell`s.
 pub struct Root {
     tag: Tag,
+    stats: StatsCell,
+    live: live::LiveObjects,
+    #[cfg(feature = "journal")]
+    journal: journal::Journal,
 
     // RootedRc and RootedRefCell rely on `Root` being `!Sync`. They take
     // immutable/shared references to Self to prove that no other thread
     // currently has access.
-    _notsync: std::marker::PhantomData<std::cell::Cell<()>>,
+    _notsync: core::marker::PhantomData<core::cell::Cell<()>>,
 }
 
 impl Root {
     pub fn new() -> Self {
         let tag = Tag::new();
+        debug_event!(tag = ?tag, "created root");
         Self {
             tag,
+            stats: StatsCell::default(),
+            live: live::LiveObjects::default(),
+            #[cfg(feature = "journal")]
+            journal: journal::Journal::default(),
             _notsync: PhantomData,
         }
     }
@@ -75,6 +220,166 @@ impl Root {
     fn tag(&self) -> Tag {
         self.tag
     }
+
+    /// This root's identifier, which no other root shares.
+    pub fn id(&self) -> RootId {
+        RootId(self.tag)
+    }
+
+    /// Gives up this root so that it can be reconstructed with `from_id`,
+    /// typically in another process sharing objects with this one via the
+    /// `shmem` module. Unlike dropping it, objects still associated with the
+    /// root aren't reported as leaked, since they remain usable through the
+    /// reconstructed root.
+    ///
+    /// Any active checkpoints are released, as when the root is dropped. The
+    /// `stats` counters and the `leak_check` record of live objects are
+    /// discarded; the reconstructed root only counts objects created or
+    /// released through it.
+    pub fn into_id(self) -> RootId {
+        let mut this = core::mem::ManuallyDrop::new(self);
+        #[cfg(feature = "journal")]
+        this.journal.clear(&this);
+        // Free the fields' heap memory; the replacements own none.
+        this.live = live::LiveObjects::default();
+        #[cfg(feature = "journal")]
+        {
+            this.journal = journal::Journal::default();
+        }
+        this.id()
+    }
+
+    /// Reconstructs a root given up with `into_id`.
+    ///
+    /// # Safety
+    ///
+    /// No other `Root` with this id may exist, in this or any other process,
+    /// until the returned one is dropped or given up again. i.e. `id` must
+    /// have been returned by `into_id` and not already passed to `from_id`,
+    /// and the handover must be synchronized, e.g. by a lock in the shared
+    /// memory through which `id` was passed. Otherwise two threads could
+    /// access the same objects in parallel.
+    pub unsafe fn from_id(id: RootId) -> Self {
+        debug_event!(tag = ?id.0, "reconstructed root");
+        Self {
+            tag: id.0,
+            stats: StatsCell::default(),
+            live: live::LiveObjects::default(),
+            #[cfg(feature = "journal")]
+            journal: journal::Journal::default(),
+            _notsync: PhantomData,
+        }
+    }
+
+    /// Clones the object graph reachable from `value`, which must be associated
+    /// with this root, into `dst`. Each `RootedRc` allocation is copied once,
+    /// so sharing within `value` is preserved in the copy. To preserve sharing
+    /// across multiple values, clone them together as a tuple, or use a
+    /// `DeepCloneContext` directly.
+    ///
+    /// Panics if any object reachable from `value` is associated with another
+    /// root, is currently mutably borrowed, or is part of a reference cycle.
+    pub fn deep_clone_into<T: DeepClone>(&self, dst: &Root, value: &T) -> T {
+        value.deep_clone(&mut DeepCloneContext::new(self, dst))
+    }
+
+    /// Snapshot of the operations performed under this root so far.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> RootStats {
+        self.stats.get()
+    }
+
+    /// Resets the operation counters, e.g. at the start of a simulation round.
+    /// Counts of currently live objects and outstanding borrows are kept, and
+    /// become the new high-water marks.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// Starts recording mutations made with
+    /// `RootedRc::borrow_mut_journaled`, so that they can later be undone
+    /// with `rollback`. Checkpoints nest; each must eventually be passed to
+    /// either `rollback` or `release_checkpoint`.
+    ///
+    /// Only a cell held directly in a `RootedRc`, with `Clone + Send` contents,
+    /// can be journaled. While any checkpoint is active, every other mutable
+    /// borrow of a cell associated with this root fails with
+    /// `BorrowError::CheckpointActive` (or panics, for `borrow_mut`). That
+    /// includes cells embedded in other objects and cells whose contents
+    /// aren't `Clone`, such as a `RootedRefCell<Vec<RootedRc<_>>>`, which
+    /// therefore can't be mutated at all until the checkpoint is released.
+    #[cfg(feature = "journal")]
+    pub fn checkpoint(&self) -> CheckpointId {
+        self.journal.checkpoint()
+    }
+
+    /// Undoes all journaled mutations since `checkpoint` was created, and
+    /// releases it along with any checkpoints created after it.
+    ///
+    /// Panics if `checkpoint` has already been released, or if any of the
+    /// restored objects are currently borrowed.
+    #[cfg(feature = "journal")]
+    pub fn rollback(&self, checkpoint: CheckpointId) {
+        self.journal.rollback(self, checkpoint)
+    }
+
+    /// Releases `checkpoint`, along with any checkpoints created after it,
+    /// keeping the mutations made since. Mutations remain recorded if an
+    /// older checkpoint is still active.
+    ///
+    /// Panics if `checkpoint` has already been released.
+    #[cfg(feature = "journal")]
+    pub fn release_checkpoint(&self, checkpoint: CheckpointId) {
+        self.journal.release(self, checkpoint)
+    }
+
+    /// Drops the root if no objects still need it to be released, and
+    /// otherwise returns it. Objects recorded by an active checkpoint count
+    /// as live.
+    #[cfg(feature = "leak_check")]
+    #[allow(clippy::result_large_err)]
+    pub fn try_drop(self) -> Result<(), Root> {
+        if self.live.count() == 0 {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Reports objects that can no longer be released, since this root is
+    /// being dropped. Panics in debug builds, as for a leaked `RootedRc`.
+    #[cfg(feature = "leak_check")]
+    fn report_live_objects(&self) {
+        let count = self.live.count();
+        if count == 0 {
+            return;
+        }
+        let summary = self.live.summary();
+        log::error!("Dropped root with {count} live objects: {summary}");
+        error_event!(
+            tag = ?self.tag,
+            count,
+            objects = %summary,
+            "dropped Root with live objects"
+        );
+        #[cfg(debug_assertions)]
+        if !crate::panicking() {
+            panic!("Dropped root with {count} live objects: {summary}");
+        }
+    }
+}
+
+#[cfg(any(feature = "journal", feature = "leak_check"))]
+impl Drop for Root {
+    fn drop(&mut self) {
+        // The journal holds references to rooted objects, which need the root
+        // to be released.
+        #[cfg(feature = "journal")]
+        self.journal.clear(self);
+        #[cfg(feature = "leak_check")]
+        self.report_live_objects();
+    }
 }
 
 impl Default for Root {
@@ -83,5 +388,225 @@ impl Default for Root {
     }
 }
 
+/// Proof that the current thread has access to a `Root`. Accepted by the
+/// operations on existing objects that need the root, such as
+/// `RootedRc::clone` and `safely_drop`, the `RootedRefCell::borrow` family and
+/// `borrow_many_mut`, and the corresponding `RootedWeak`, `BiasedRootedRc` and
+/// `ShmemRootedRc` methods, so that e.g. a `MutexGuard<Root>`, or a type that
+/// holds one, can be passed directly. Constructors take a `&Root`, which a
+/// proof provides through `root`.
+///
+/// Implementing this is safe, since all it can do is hand out a `&Root`, and
+/// `Root` being `!Sync` is what guarantees that no other thread can access the
+/// root while the reference is alive. Borrows made through a proof borrow the
+/// proof itself, so they also end before it can be released.
+pub trait RootProof {
+    fn root(&self) -> &Root;
+}
+
+impl RootProof for Root {
+    fn root(&self) -> &Root {
+        self
+    }
+}
+
+/// References to roots, and smart pointers and lock guards of them, such as
+/// `MutexGuard<Root>`.
+impl<D: core::ops::Deref<Target = Root>> RootProof for D {
+    fn root(&self) -> &Root {
+        self
+    }
+}
+
+pub mod biased;
+pub mod deep_clone;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod rc;
 pub mod refcell;
+pub mod shmem;
+
+#[cfg(all(test, not(loom)))]
+mod test_tag {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn allocator(seed: TagPrefixType, next: u64) -> TagAllocator {
+        TagAllocator {
+            seed: OnceBox::with_value(Box::new(seed)),
+            next: AtomicU64::new(next),
+        }
+    }
+
+    #[test]
+    fn tags_are_unique() {
+        let tags: HashSet<Tag> = (0..1000).map(|_| Tag::new()).collect();
+        assert_eq!(tags.len(), 1000);
+    }
+
+    #[test]
+    fn suffix_overflow_rerolls_prefix() {
+        let alloc = allocator(0, (u64::MAX >> (u64::BITS - TagSuffixType::BITS)) - 1);
+        let n = if TagSuffixType::BITS < u64::BITS {
+            4
+        } else {
+            2
+        };
+        let tags: Vec<Tag> = (0..n).map(|_| alloc.next_tag()).collect();
+
+        assert_eq!(tags[0].prefix, tags[1].prefix);
+        assert_eq!(tags[1].suffix, TagSuffixType::MAX);
+        if TagSuffixType::BITS < u64::BITS {
+            // The suffix wrapped around, with a new prefix.
+            assert_eq!(tags[2].suffix, 0);
+            assert_ne!(tags[1].prefix, tags[2].prefix);
+        }
+        let unique: HashSet<&Tag> = tags.iter().collect();
+        assert_eq!(unique.len(), tags.len());
+    }
+
+    #[test]
+    fn instances_in_one_process_dont_collide() {
+        // Simulate multiple copies of this crate linked into one process, each
+        // with its own seed and counter.
+        let instances: Vec<TagAllocator> = (0..8).map(|i| allocator(i, 0)).collect();
+        let mut tags = HashSet::new();
+        for _ in 0..100 {
+            for instance in &instances {
+                assert!(tags.insert(instance.next_tag()));
+            }
+        }
+    }
+
+    #[test]
+    fn seed_source_is_fixed_once_used() {
+        Tag::new();
+        fn zero() -> u64 {
+            0
+        }
+        assert!(set_tag_seed_source(zero).is_err());
+    }
+
+    #[test]
+    fn instances_with_random_seeds_dont_collide() {
+        let instances: Vec<TagAllocator> = (0..8).map(|_| TagAllocator::new()).collect();
+        let mut tags = HashSet::new();
+        for _ in 0..100 {
+            for instance in &instances {
+                assert!(tags.insert(instance.next_tag()));
+            }
+        }
+    }
+}
+
+// Model checks of concurrent tag allocation. Run with `maint/checks/loom.sh`.
+#[cfg(all(test, loom))]
+mod test_loom_tag {
+    use std::collections::HashSet;
+
+    use loom::sync::Arc;
+    use loom::thread;
+
+    use super::*;
+
+    fn next_tags_concurrently(alloc: TagAllocator) -> Vec<Tag> {
+        let alloc = Arc::new(alloc);
+        let handle = {
+            let alloc = alloc.clone();
+            thread::spawn(move || alloc.next_tag())
+        };
+        let tag = alloc.next_tag();
+        vec![tag, handle.join().unwrap()]
+    }
+
+    #[test]
+    fn racing_threads_share_seed() {
+        loom::model(|| {
+            // Each thread that races to initialize the seed draws a different
+            // random one, but only the winner's may be used.
+            let tags = next_tags_concurrently(TagAllocator::new());
+            assert_eq!(tags[0].prefix, tags[1].prefix);
+            assert_ne!(tags[0].suffix, tags[1].suffix);
+        });
+    }
+
+    #[test]
+    fn racing_threads_across_epoch_get_unique_tags() {
+        if TagSuffixType::BITS == u64::BITS {
+            // There's only one epoch.
+            return;
+        }
+        loom::model(|| {
+            let alloc = TagAllocator::new();
+            alloc.seed.set(Box::new(0)).unwrap();
+            alloc
+                .next
+                .store(TagSuffixType::MAX as u64, Ordering::Relaxed);
+            let tags = next_tags_concurrently(alloc);
+            // One thread takes the last tag of the first epoch, and the other
+            // the first tag of the next, with a new prefix.
+            let suffixes: HashSet<_> = tags.iter().map(|tag| tag.suffix).collect();
+            assert_eq!(suffixes, HashSet::from([TagSuffixType::MAX, 0]));
+            assert_ne!(tags[0].prefix, tags[1].prefix);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_root_proof {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::rc::RootedRc;
+    use crate::refcell::RootedRefCell;
+
+    struct HostGuard<'a> {
+        root: std::sync::MutexGuard<'a, Root>,
+    }
+
+    impl RootProof for HostGuard<'_> {
+        fn root(&self) -> &Root {
+            &self.root
+        }
+    }
+
+    #[test]
+    fn lock_guards_are_proofs() {
+        let root = Mutex::new(Root::new());
+        let guard = root.lock().unwrap();
+        let rc = RootedRc::new(&guard, RootedRefCell::new(&guard, 0));
+        let rc2 = rc.clone(&guard);
+        *rc.borrow_mut(&guard) += 1;
+        drop(guard);
+
+        let host = HostGuard {
+            root: root.lock().unwrap(),
+        };
+        assert_eq!(*rc2.borrow(&host), 1);
+        rc.safely_drop(&host);
+        let weak = RootedRc::downgrade(&rc2, &host);
+        weak.safely_drop(&host);
+        let mut rc2 = rc2;
+        assert!(RootedRc::get_mut(&mut rc2, &host).is_some());
+        rc2.safely_drop(&host);
+    }
+
+    #[test]
+    fn proofs_for_other_operations() {
+        let root = Mutex::new(Root::new());
+        let host = HostGuard {
+            root: root.lock().unwrap(),
+        };
+        let a = RootedRefCell::new(host.root(), 0);
+        let b = RootedRefCell::new(host.root(), 0);
+        let [mut a_guard, b_guard] = crate::refcell::borrow_many_mut(&host, [&a, &b]).unwrap();
+        *a_guard = 1;
+        drop((a_guard, b_guard));
+        assert_eq!(*a.borrow(&host), 1);
+
+        let rc = crate::biased::BiasedRootedRc::new(host.root(), 0);
+        rc.clone(&host).safely_drop(&host);
+        rc.safely_drop(&host);
+    }
+}
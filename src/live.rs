@@ -0,0 +1,202 @@
+//! Tracking of the objects that still need a root to be released, enabled by
+//! the `leak_check` feature.
+//!
+//! Each `RootedRc` and `ShmemRootedRc` allocation, and each `BiasedRootedRc`
+//! allocation while it has rooted references, is recorded in its root from
+//! creation until it's released with `safely_drop`. If the root is dropped
+//! first, those objects can never be released, so `Root`'s `Drop` reports
+//! them. As for the `stats`
+//! counters, the record is kept in plain cells inside the `Root`, and only
+//! updated by operations that already prove access to it.
+//!
+//! By default only the number of live objects is kept, so recording an object
+//! costs an increment. The `leak_check_types` feature also records the type of
+//! each object, at the cost of a map insert and remove per allocation, so that
+//! the report can say what was leaked.
+//!
+//! `ShmemRootedRc` blocks may be released through a different mapping of
+//! their region than they were created through, so `leak_check_types` records
+//! them by offset and type rather than by address, and counts them so that
+//! blocks at the same offset in different regions don't collide.
+//!
+//! Without the `leak_check` feature the record is compiled out entirely.
+
+#[cfg(feature = "leak_check_types")]
+use alloc::collections::BTreeMap;
+#[cfg(feature = "leak_check")]
+use core::cell::Cell;
+#[cfg(feature = "leak_check_types")]
+use core::cell::RefCell;
+
+/// Record of live objects embedded in each `Root`. Zero-sized when the
+/// `leak_check` feature is disabled, in which case all methods are no-ops.
+#[derive(Default)]
+pub(crate) struct LiveObjects {
+    #[cfg(feature = "leak_check")]
+    count: Cell<usize>,
+    // The type of each object, keyed by the address of its allocation.
+    #[cfg(feature = "leak_check_types")]
+    objects: RefCell<BTreeMap<usize, &'static str>>,
+    // The number of `ShmemRootedRc` blocks of each type at each offset.
+    #[cfg(feature = "leak_check_types")]
+    shmem_objects: RefCell<BTreeMap<(usize, &'static str), usize>>,
+}
+
+impl LiveObjects {
+    /// Records the allocation at `ptr`, holding a value of type `ty`.
+    #[inline]
+    pub fn add<P>(&self, ptr: *const P, ty: &'static str) {
+        #[cfg(feature = "leak_check_types")]
+        self.objects.borrow_mut().insert(ptr as usize, ty);
+        #[cfg(feature = "leak_check")]
+        self.inc();
+        #[cfg(not(feature = "leak_check_types"))]
+        let _ = (ptr, ty);
+    }
+
+    /// Records that the allocation at `ptr` no longer needs the root.
+    #[inline]
+    pub fn remove<P>(&self, ptr: *const P) {
+        #[cfg(feature = "leak_check_types")]
+        self.objects.borrow_mut().remove(&(ptr as usize));
+        #[cfg(feature = "leak_check")]
+        self.dec();
+        #[cfg(not(feature = "leak_check_types"))]
+        let _ = ptr;
+    }
+
+    /// Records the shared memory block at `offset`, holding a value of type
+    /// `ty`.
+    #[inline]
+    pub fn add_shmem(&self, offset: usize, ty: &'static str) {
+        #[cfg(feature = "leak_check_types")]
+        {
+            *self
+                .shmem_objects
+                .borrow_mut()
+                .entry((offset, ty))
+                .or_default() += 1;
+        }
+        #[cfg(feature = "leak_check")]
+        self.inc();
+        #[cfg(not(feature = "leak_check_types"))]
+        let _ = (offset, ty);
+    }
+
+    /// Records that the shared memory block at `offset`, holding a value of
+    /// type `ty`, no longer needs the root.
+    #[inline]
+    pub fn remove_shmem(&self, offset: usize, ty: &'static str) {
+        #[cfg(feature = "leak_check_types")]
+        {
+            let mut objects = self.shmem_objects.borrow_mut();
+            if let Some(n) = objects.get_mut(&(offset, ty)) {
+                *n -= 1;
+                if *n == 0 {
+                    objects.remove(&(offset, ty));
+                }
+            }
+        }
+        #[cfg(feature = "leak_check")]
+        self.dec();
+        #[cfg(not(feature = "leak_check_types"))]
+        let _ = (offset, ty);
+    }
+
+    #[cfg(feature = "leak_check")]
+    #[inline]
+    fn inc(&self) {
+        self.count.set(self.count.get() + 1);
+    }
+
+    // Saturating, since objects created under a previous owner of a handed
+    // over root were never recorded in this one.
+    #[cfg(feature = "leak_check")]
+    #[inline]
+    fn dec(&self) {
+        self.count.set(self.count.get().saturating_sub(1));
+    }
+
+    #[cfg(feature = "leak_check")]
+    pub fn count(&self) -> usize {
+        self.count.get()
+    }
+
+    /// Describes the live objects, e.g. "2 x foo::Bar, 1 x u32". Without the
+    /// `leak_check_types` feature only their number is known.
+    #[cfg(feature = "leak_check")]
+    pub fn summary(&self) -> alloc::string::String {
+        #[cfg(feature = "leak_check_types")]
+        {
+            use core::fmt::Write;
+
+            let mut counts = BTreeMap::<&str, usize>::new();
+            for ty in self.objects.borrow().values() {
+                *counts.entry(ty).or_default() += 1;
+            }
+            for ((_, ty), n) in self.shmem_objects.borrow().iter() {
+                *counts.entry(ty).or_default() += n;
+            }
+            let mut summary = alloc::string::String::new();
+            for (i, (ty, n)) in counts.iter().enumerate() {
+                let sep = if i == 0 { "" } else { ", " };
+                let _ = write!(summary, "{sep}{n} x {ty}");
+            }
+            summary
+        }
+        #[cfg(not(feature = "leak_check_types"))]
+        alloc::string::String::from("types not recorded without `leak_check_types`")
+    }
+}
+
+#[cfg(all(test, feature = "leak_check"))]
+mod test_live_objects {
+    use crate::biased::BiasedRootedRc;
+    use crate::rc::RootedRc;
+    use crate::Root;
+
+    #[test]
+    fn try_drop_with_live_objects() {
+        let root = Root::new();
+        let a = RootedRc::new(&root, 0u32);
+        let b = a.clone(&root);
+        let weak = RootedRc::downgrade(&a, &root);
+        let biased = BiasedRootedRc::new(&root, 'x');
+        let unrooted = biased.clone_unrooted();
+        let root = root.try_drop().err().unwrap();
+
+        a.safely_drop(&root);
+        b.safely_drop(&root);
+        biased.safely_drop(&root);
+        // Only the weak reference still needs the root.
+        let root = root.try_drop().err().unwrap();
+        weak.safely_drop(&root);
+        assert!(root.try_drop().is_ok());
+        // Unrooted references don't need the root.
+        assert_eq!(*unrooted, 'x');
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Dropped root with 3 live objects")]
+    fn drop_with_live_objects_panics_with_count() {
+        let root = Root::new();
+        let a = RootedRc::new(&root, 'a');
+        let b = RootedRc::new(&root, 'b');
+        let c = RootedRc::new(&root, 0u32);
+        core::mem::forget((a, b, c));
+        drop(root);
+    }
+
+    #[cfg(all(debug_assertions, feature = "leak_check_types"))]
+    #[test]
+    #[should_panic(expected = "2 x char, 1 x u32")]
+    fn drop_with_live_objects_panics() {
+        let root = Root::new();
+        let a = RootedRc::new(&root, 'a');
+        let b = RootedRc::new(&root, 'b');
+        let c = RootedRc::new(&root, 0u32);
+        core::mem::forget((a, b, c));
+        drop(root);
+    }
+}
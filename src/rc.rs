@@ -1,25 +1,127 @@
-use crate::{Root, Tag};
-use std::cell::Cell;
+use core::alloc::Layout;
+use core::mem::{offset_of, ManuallyDrop, MaybeUninit};
+use core::pin::Pin;
+use core::ptr::{addr_of, addr_of_mut, NonNull};
 
-struct RootedRcInternal<T> {
-    val: T,
-    strong_count: Cell<u32>,
+use allocator_api2::alloc::{Allocator, Global};
+
+use crate::deep_clone::{DeepClone, DeepCloneContext};
+use crate::refcell::RootedRefCell;
+use crate::sync::Count;
+use crate::{Root, RootId, RootProof, Tag};
+
+// `repr(C)` so that `RootedRcInternal<MaybeUninit<T>, A>` has the same layout
+// as `RootedRcInternal<T, A>`. See `RootedRc::assume_init`.
+#[repr(C)]
+struct RootedRcInternal<T, A> {
+    // Tag of the associated root, so that `RootedRc::from_raw` can check it.
+    tag: Tag,
+    strong_count: Count,
+    // Number of `RootedWeak`s, plus one shared by all of the strong references
+    // while any exist.
+    weak_count: Count,
+    // Kept in the block itself, so that it's still available to free the
+    // block when the last reference is dropped.
+    alloc: A,
+    // Dropped in place when the strong count reaches zero. The block itself is
+    // freed when the weak count does.
+    val: ManuallyDrop<T>,
 }
 
-impl<T> RootedRcInternal<T> {
-    pub fn new(val: T) -> Self {
-        Self {
-            val,
-            strong_count: Cell::new(1),
+// These take raw pointers rather than `&self`, since the value may be
+// uninitialized (in `RootedRc::new_cyclic`) or already dropped (once only weak
+// references remain).
+impl<T, A: Allocator> RootedRcInternal<T, A> {
+    /// Allocates a block for `root` from `alloc` with a strong count of
+    /// `strong` and no weak references, leaving the value uninitialized.
+    fn allocate(root: &Root, alloc: A, strong: u32) -> NonNull<Self> {
+        let layout = Layout::new::<Self>();
+        let Ok(ptr) = alloc.allocate(layout) else {
+            alloc::alloc::handle_alloc_error(layout);
+        };
+        let this = ptr.cast::<Self>().as_ptr();
+        // SAFETY: `allocate` returned memory valid for `layout`.
+        unsafe {
+            addr_of_mut!((*this).tag).write(root.tag());
+            addr_of_mut!((*this).strong_count).write(Count::new(strong));
+            addr_of_mut!((*this).weak_count).write(Count::new(1));
+            addr_of_mut!((*this).alloc).write(alloc);
         }
+        root.live.add(this, core::any::type_name::<T>());
+        ptr.cast()
     }
 
-    pub fn inc_strong(&self) {
-        self.strong_count.set(self.strong_count.get() + 1)
+    /// The block containing the value at `val`.
+    ///
+    /// # Safety
+    ///
+    /// `val` must point to the value of a block, e.g. as returned by
+    /// `RootedRc::into_raw`.
+    unsafe fn from_val(val: *const T) -> *mut Self {
+        unsafe { val.byte_sub(offset_of!(Self, val)) as *mut Self }
     }
 
-    pub fn dec_strong(&self) {
-        self.strong_count.set(self.strong_count.get() - 1)
+    /// # Safety
+    ///
+    /// `this` must point to a block that hasn't been freed.
+    unsafe fn strong<'a>(this: *const Self) -> &'a Count {
+        unsafe { &*addr_of!((*this).strong_count) }
+    }
+
+    /// # Safety
+    ///
+    /// `this` must point to a block that hasn't been freed.
+    unsafe fn weak<'a>(this: *const Self) -> &'a Count {
+        unsafe { &*addr_of!((*this).weak_count) }
+    }
+
+    /// Drops the value, after the last strong reference has been released.
+    ///
+    /// # Safety
+    ///
+    /// The value must be initialized, and must not be accessed again. The
+    /// caller must have access to the root.
+    unsafe fn drop_val(this: *mut Self) {
+        // Dropped in place rather than moved out, as `RootedRc::pin` promises.
+        unsafe { ManuallyDrop::drop(&mut *addr_of_mut!((*this).val)) }
+    }
+
+    /// Releases a weak reference, including the implicit one held by the
+    /// strong references, freeing the block if it was the last.
+    ///
+    /// # Safety
+    ///
+    /// `this` must point to a block that hasn't been freed, and the reference
+    /// must not be used again. `root` must be the block's root.
+    unsafe fn release_weak(this: *mut Self, root: &Root) {
+        if unsafe { Self::weak(this) }.dec() == 0 {
+            root.live.remove(this);
+            // SAFETY: No references remain, and the value has already been
+            // dropped. The counts are trivial to drop except under loom, which
+            // tracks them.
+            unsafe {
+                core::ptr::drop_in_place(addr_of_mut!((*this).strong_count));
+                core::ptr::drop_in_place(addr_of_mut!((*this).weak_count));
+                let alloc = addr_of!((*this).alloc).read();
+                alloc.deallocate(
+                    NonNull::new_unchecked(this as *mut u8),
+                    Layout::new::<Self>(),
+                );
+            }
+        }
+    }
+}
+
+/// Panics if `root` isn't the `Root` with tag `tag`.
+#[track_caller]
+fn check_root(root: &Root, tag: Tag) {
+    if root.tag != tag {
+        error_event!(
+            root = ?root.tag,
+            expected = ?tag,
+            "used RootedRc with wrong root"
+        );
+        panic!("Tried using a lock for {:?} instead of {:?}", root.tag, tag);
     }
 }
 
@@ -35,35 +137,301 @@ impl<T> RootedRcInternal<T> {
 /// that the lock is held before manipulating reference counts, etc.
 /// Failing to call `safely_drop` results in a `panic` in debug builds,
 /// or leaking the object in release builds.
-pub struct RootedRc<T> {
-    tag: Tag,
-    internal: *mut RootedRcInternal<T>,
+///
+/// Like `Rc`, the internal block is allocated from `A`, which defaults to the
+/// global allocator. See `new_in`.
+pub struct RootedRc<T, A: Allocator = Global> {
+    // The tag is kept in the block rather than here, so that handles are a
+    // single pointer (and `Option<RootedRc<T>>` is too).
+    internal: NonNull<RootedRcInternal<T, A>>,
 }
 
 impl<T> RootedRc<T> {
     /// Creates a new object associated with `root`.
     pub fn new(root: &Root, val: T) -> Self {
-        Self {
-            tag: root.tag(),
-            internal: Box::into_raw(Box::new(RootedRcInternal::new(val))),
+        Self::new_in(root, val, Global)
+    }
+
+    /// Creates a new object associated with `root`, whose value can refer
+    /// back to it. `data_fn` is passed a weak reference to the object being
+    /// created, which it may clone (e.g. to store in the returned value).
+    /// Upgrading it returns `None` until `new_cyclic` returns.
+    ///
+    /// If `data_fn` panics, the allocation is leaked.
+    pub fn new_cyclic(root: &Root, data_fn: impl FnOnce(&RootedWeak<T>) -> T) -> Self {
+        Self::new_cyclic_in(root, Global, data_fn)
+    }
+
+    /// Creates a new object associated with `root`, with uninitialized
+    /// contents. Initialize them through `get_mut`, and then convert the
+    /// object with `assume_init`.
+    pub fn new_uninit(root: &Root) -> RootedRc<MaybeUninit<T>> {
+        RootedRc::new(root, MaybeUninit::uninit())
+    }
+
+    /// Creates a new pinned object associated with `root`.
+    ///
+    /// The value is never moved: it stays at the same address until it's
+    /// dropped in place, when the last reference is released with
+    /// `safely_drop_pinned`. If a reference is leaked instead, the value is
+    /// never freed. Pinned references can't be downgraded, and don't give
+    /// access to `get_mut`.
+    pub fn pin(root: &Root, val: T) -> Pin<Self> {
+        // SAFETY: As above; no API exposes the value mutably, or moves it out,
+        // while it may still be referenced through a `Pin`.
+        unsafe { Pin::new_unchecked(Self::new(root, val)) }
+    }
+}
+
+impl<T, A: Allocator> RootedRc<MaybeUninit<T>, A> {
+    /// Converts to a `RootedRc<T>`, like `MaybeUninit::assume_init`.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been initialized.
+    pub unsafe fn assume_init(self) -> RootedRc<T, A> {
+        let this = ManuallyDrop::new(self);
+        RootedRc {
+            // `RootedRcInternal` is `repr(C)`, and `ManuallyDrop<MaybeUninit<T>>`
+            // has the same layout as `ManuallyDrop<T>`.
+            internal: this.internal.cast(),
+        }
+    }
+}
+
+impl<T, A: Allocator> RootedRc<RootedRefCell<T>, A> {
+    /// Returns a mutable reference to the value in the shared cell, without
+    /// updating its borrow state or the reference counts. See
+    /// `RootedRefCell::get_mut_with`. Unlike `get_mut`, other `RootedRc` and
+    /// `RootedWeak` references may exist: holding `root` mutably rules out
+    /// any access through them until the returned reference is dropped.
+    ///
+    /// Panics if `root` is for the wrong `Root`. Like `get_mut`, this is an
+    /// associated function so that it doesn't shadow a method of `T`.
+    pub fn get_mut_with<'a>(this: &'a Self, root: &'a mut Root) -> &'a mut T {
+        (**this).get_mut_with(root)
+    }
+}
+
+impl<T, A: Allocator> RootedRc<T, A> {
+    /// Creates a new object associated with `root`, allocated from `alloc`.
+    ///
+    /// `Allocator` is `allocator_api2`'s stable version of the unstable
+    /// `core::alloc::Allocator`; with the `nightly` feature they are the
+    /// same trait.
+    pub fn new_in(root: &Root, val: T, alloc: A) -> Self {
+        let internal = RootedRcInternal::<T, A>::allocate(root, alloc, 1);
+        // SAFETY: The block was just allocated.
+        unsafe { addr_of_mut!((*internal.as_ptr()).val).write(ManuallyDrop::new(val)) };
+        root.stats.rc_alloc();
+        trace_event!(
+            tag = ?root.tag(),
+            ptr = ?internal,
+            ty = core::any::type_name::<T>(),
+            "allocated RootedRc"
+        );
+        Self { internal }
+    }
+
+    /// Like `new_cyclic`, allocating from `alloc`.
+    pub fn new_cyclic_in(
+        root: &Root,
+        alloc: A,
+        data_fn: impl FnOnce(&RootedWeak<T, A>) -> T,
+    ) -> Self {
+        let internal = RootedRcInternal::<T, A>::allocate(root, alloc, 0);
+        let weak = RootedWeak { internal };
+        let val = data_fn(&weak);
+        // SAFETY: The block is live, since we still hold `weak`. The value
+        // isn't accessible through any weak references until the strong count
+        // is nonzero.
+        unsafe {
+            addr_of_mut!((*internal.as_ptr()).val).write(ManuallyDrop::new(val));
+            RootedRcInternal::strong(internal.as_ptr()).set(1);
+        }
+        // Becomes the implicit weak reference shared by the strong ones.
+        core::mem::forget(weak);
+        root.stats.rc_alloc();
+        trace_event!(
+            tag = ?root.tag(),
+            ptr = ?internal,
+            ty = core::any::type_name::<T>(),
+            "allocated RootedRc"
+        );
+        Self { internal }
+    }
+
+    /// The allocator the internal block was allocated from.
+    ///
+    /// This is an associated function, like `Rc::allocator`, so that it
+    /// doesn't shadow a method of `T`.
+    pub fn allocator(this: &Self) -> &A {
+        // SAFETY: pointer points to valid data by construction.
+        &unsafe { this.internal.as_ptr().as_ref() }.unwrap().alloc
+    }
+
+    /// The identifier of the `Root` this object is associated with. Like
+    /// `allocator`, this is an associated function so that it doesn't shadow
+    /// a method of `T`.
+    pub fn root_id(this: &Self) -> RootId {
+        RootId(this.tag())
+    }
+
+    /// Whether this object is associated with `root`, i.e. whether methods
+    /// taking `root` would succeed rather than panic.
+    pub fn belongs_to(this: &Self, root: &Root) -> bool {
+        root.tag == this.tag()
+    }
+
+    /// Returns a mutable reference to the value, if there are no other
+    /// `RootedRc` or `RootedWeak` references to it. Like `Rc::get_mut`, this
+    /// is an associated function so that it doesn't shadow a method of `T`.
+    ///
+    /// Panics if `root` is for the wrong `Root`.
+    pub fn get_mut<'a, P: RootProof + ?Sized>(this: &'a mut Self, root: &P) -> Option<&'a mut T> {
+        check_root(root.root(), this.tag());
+        // SAFETY: We hold the root, so the counts can't be changing. If
+        // there are no other references, none can be created while `this` is
+        // mutably borrowed.
+        unsafe {
+            if RootedRcInternal::strong(this.internal.as_ptr()).get() == 1
+                && RootedRcInternal::weak(this.internal.as_ptr()).get() == 1
+            {
+                Some(&mut (*this.internal.as_ptr()).val)
+            } else {
+                None
+            }
         }
     }
 
+    /// Creates a weak reference to this object, which doesn't keep the value
+    /// alive. Like `Rc::downgrade`, this is an associated function so that it
+    /// doesn't shadow a method of `T`.
+    ///
+    /// Panics if `root` is for the wrong `Root`.
+    pub fn downgrade<P: RootProof + ?Sized>(this: &Self, root: &P) -> RootedWeak<T, A> {
+        let root = root.root();
+        check_root(root, this.tag());
+        // SAFETY: We hold the root, so no other thread is accessing the count.
+        unsafe { RootedRcInternal::weak(this.internal.as_ptr()) }.inc();
+        RootedWeak {
+            internal: this.internal,
+        }
+    }
+
+    /// Consumes the `RootedRc`, returning a pointer to the value. The
+    /// reference it held is kept, and must eventually be released by
+    /// converting the pointer back with `from_raw`, or with
+    /// `decrement_strong_count`.
+    ///
+    /// Like `Rc::into_raw`, this is an associated function so that it
+    /// doesn't shadow a method of `T`.
+    pub fn into_raw(this: Self) -> *const T {
+        let ptr = Self::as_ptr(&this);
+        core::mem::forget(this);
+        ptr
+    }
+
+    /// A pointer to the value, valid for as long as any strong reference
+    /// remains.
+    pub fn as_ptr(this: &Self) -> *const T {
+        // SAFETY: The block is live as long as `this` is.
+        unsafe { addr_of!((*this.internal.as_ptr()).val) as *const T }
+    }
+
+    /// Reconstructs a `RootedRc` from a pointer returned by `into_raw`, taking
+    /// over the reference it held.
+    ///
+    /// Panics if `root` is for the wrong `Root`. The tag is stored alongside
+    /// the value, so this is checked even though the pointer doesn't carry it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `RootedRc::<T, A>::into_raw`, and its
+    /// reference not already reclaimed.
+    pub unsafe fn from_raw<P: RootProof + ?Sized>(root: &P, ptr: *const T) -> Self {
+        // SAFETY: Guaranteed by caller.
+        let internal = unsafe { NonNull::new_unchecked(RootedRcInternal::<T, A>::from_val(ptr)) };
+        // Checked before `Self` exists, so that a failure doesn't drop one
+        // without `safely_drop`. SAFETY: The block is live, and the tag is
+        // immutable.
+        check_root(root.root(), unsafe {
+            addr_of!((*internal.as_ptr()).tag).read()
+        });
+        Self { internal }
+    }
+
+    /// Increments the strong count of the object at `ptr`, as if by
+    /// `clone`. Like `Rc::increment_strong_count`, for use with `into_raw`
+    /// and `from_raw`.
+    ///
+    /// Panics if `root` is for the wrong `Root`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `RootedRc::<T, A>::into_raw`, and
+    /// at least one strong reference must still be held.
+    pub unsafe fn increment_strong_count<P: RootProof + ?Sized>(root: &P, ptr: *const T) {
+        // SAFETY: Guaranteed by caller.
+        let this = ManuallyDrop::new(unsafe { Self::from_raw(root, ptr) });
+        core::mem::forget(this.clone(root));
+    }
+
+    /// Decrements the strong count of the object at `ptr`, as if by
+    /// `safely_drop`, dropping the value if it was the last strong reference.
+    ///
+    /// Panics if `root` is for the wrong `Root`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `RootedRc::<T, A>::into_raw`, and
+    /// the reference being released must not be used again.
+    pub unsafe fn decrement_strong_count<P: RootProof + ?Sized>(root: &P, ptr: *const T) {
+        // SAFETY: Guaranteed by caller.
+        unsafe { Self::from_raw(root, ptr) }.safely_drop(root)
+    }
+
+    /// Like `clone`, for a pinned object.
+    ///
+    /// Panics if `root` is for the wrong `Root`.
+    pub fn clone_pinned<P: RootProof + ?Sized>(this: &Pin<Self>, root: &P) -> Pin<Self> {
+        // SAFETY: `Pin` is `repr(transparent)`. The clone refers to the same
+        // pinned value.
+        unsafe {
+            let this = &*(this as *const Pin<Self> as *const Self);
+            Pin::new_unchecked(this.clone(root))
+        }
+    }
+
+    /// Like `safely_drop`, for a pinned object.
+    ///
+    /// Panics if `root` is for the wrong `Root`.
+    pub fn safely_drop_pinned<P: RootProof + ?Sized>(this: Pin<Self>, root: &P) {
+        // SAFETY: `safely_drop` drops the value in place, if at all.
+        unsafe { Pin::into_inner_unchecked(this) }.safely_drop(root)
+    }
+
+    /// Tag of the `Root` this object is associated with.
+    pub(crate) fn tag(&self) -> Tag {
+        // SAFETY: The block is live as long as `self` is, and the tag is
+        // immutable.
+        unsafe { addr_of!((*self.internal.as_ptr()).tag).read() }
+    }
+
     /// Like Clone::clone, but requires that the corresponding Root is locked.
     ///
     /// Intentionally named clone to shadow Self::deref()::clone().
     ///
     /// Panics if `guard` did not originate from the associated `Root`.
-    pub fn clone(&self, root: &Root) -> Self {
-        assert_eq!(
-            root.tag, self.tag,
-            "Tried using a lock for {:?} instead of {:?}",
-            root.tag, self.tag
-        );
+    pub fn clone<P: RootProof + ?Sized>(&self, root: &P) -> Self {
+        let root = root.root();
+        check_root(root, self.tag());
         // SAFETY: We've verified that the lock is held by inspection of the
         // lock itself. We hold a reference to the guard, guaranteeing that the
         // lock is held while `unchecked_clone` runs.
-        unsafe { self.unchecked_clone() }
+        let clone = unsafe { self.unchecked_clone() };
+        root.stats.rc_clone();
+        clone
     }
 
     /// # Safety
@@ -72,10 +440,14 @@ impl<T> RootedRc<T> {
     unsafe fn unchecked_clone(&self) -> Self {
         // SAFETY: Pointer should be valid by construction. Caller is
         // responsible for ensuring no parallel access.
-        let internal = unsafe { self.internal.as_ref().unwrap() };
-        internal.inc_strong();
+        let _strong_count = unsafe { RootedRcInternal::strong(self.internal.as_ptr()) }.inc();
+        trace_event!(
+            tag = ?self.tag(),
+            ptr = ?self.internal,
+            strong_count = _strong_count,
+            "cloned RootedRc"
+        );
         Self {
-            tag: self.tag,
             internal: self.internal,
         }
     }
@@ -87,49 +459,121 @@ impl<T> RootedRc<T> {
     /// safely cleaned up. In debug builds this will result in a `panic`.
     /// Otherwise the underlying reference count will simply not be decremented,
     /// ultimately resulting in the enclosed value never being dropped.
-    pub fn safely_drop(mut self, root: &Root) {
-        assert_eq!(
-            root.tag, self.tag,
-            "Tried using a lock for {:?} instead of {:?}",
-            root.tag, self.tag
-        );
+    pub fn safely_drop<P: RootProof + ?Sized>(self, root: &P) {
+        let root = root.root();
+        // Read up front, since the block may be freed below.
+        let tag = self.tag();
+        check_root(root, tag);
         let drop_internal = {
             // SAFETY: pointer points to valid data by construction.
-            let internal = unsafe { self.internal.as_ref() }.unwrap();
-            internal.dec_strong();
-            internal.strong_count.get() == 0
+            let strong_count = unsafe { RootedRcInternal::strong(self.internal.as_ptr()) }.dec();
+            trace_event!(
+                tag = ?tag,
+                ptr = ?self.internal,
+                strong_count,
+                "released RootedRc"
+            );
+            strong_count == 0
         };
+        root.stats.rc_safe_drop(drop_internal);
         if drop_internal {
             // SAFETY: There are no remaining strong references to
             // self.internal, and we know that no other threads could be
-            // manipulating the reference count in parallel since we have the
+            // manipulating the reference counts in parallel since we have the
             // root lock.
-            unsafe { Box::from_raw(self.internal) };
+            unsafe {
+                RootedRcInternal::drop_val(self.internal.as_ptr());
+                RootedRcInternal::release_weak(self.internal.as_ptr(), root);
+            }
+            trace_event!(tag = ?tag, ptr = ?self.internal, "freed RootedRc");
         }
-        self.internal = std::ptr::null_mut();
+        // The reference has been released.
+        core::mem::forget(self);
     }
 }
 
-impl<T> Drop for RootedRc<T> {
+impl<T, A: Allocator> Drop for RootedRc<T, A> {
     fn drop(&mut self) {
-        if !self.internal.is_null() {
-            log::error!("Dropped without calling `safely_drop`");
-
-            // We *can* continue without violating Rust safety properties; the
-            // underlying object will just be leaked, since the ref count will
-            // never reach zero.
-            //
-            // If we're not already panicking, it's useful to panic here to make
-            // the leak more visible.
-            //
-            // If we are already panicking though, that may already explain how
-            // a call to `safely_drop` got skipped, and panicking again would
-            // just obscure the original panic.
-            #[cfg(debug_assertions)]
-            if !std::thread::panicking() {
-                panic!("Dropped without calling `safely_drop`");
-            }
+        // Only reached if `safely_drop` wasn't called, since it forgets
+        // `self`.
+        log::error!("Dropped without calling `safely_drop`");
+        error_event!(
+            tag = ?self.tag(),
+            ptr = ?self.internal,
+            ty = core::any::type_name::<T>(),
+            "leaked RootedRc dropped without calling `safely_drop`"
+        );
+
+        // We *can* continue without violating Rust safety properties; the
+        // underlying object will just be leaked, since the ref count will
+        // never reach zero.
+        //
+        // If we're not already panicking, it's useful to panic here to make
+        // the leak more visible.
+        //
+        // If we are already panicking though, that may already explain how
+        // a call to `safely_drop` got skipped, and panicking again would
+        // just obscure the original panic.
+        #[cfg(debug_assertions)]
+        if !crate::panicking() {
+            panic!("Dropped without calling `safely_drop`");
+        }
+    }
+}
+
+impl<T: DeepClone + 'static, A: Allocator + Clone + 'static> DeepClone for RootedRc<T, A> {
+    /// Copies the enclosed value into the destination root the first time
+    /// this allocation is reached; later references to the same allocation
+    /// get a clone of that copy, allocated from a clone of the same allocator.
+    fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+        check_root(ctx.src_root(), self.tag());
+        let key = self.internal.as_ptr() as *const ();
+        let dst = ctx.dst_root();
+        if let Some(weak) = ctx.copy_of::<T, A>(key) {
+            // The copy can only be dead if it's still under construction,
+            // i.e. if this reference is part of a cycle of strong references.
+            let copy = weak.upgrade(dst);
+            weak.safely_drop(dst);
+            return copy.expect("Can't deep clone a graph containing a reference cycle");
         }
+        // Allocated before the value is cloned, so that weak references back
+        // to this allocation can refer to the copy.
+        let copy = RootedRc::new_cyclic_in(dst, RootedRc::allocator(self).clone(), |weak| {
+            ctx.start_copy(key, weak.clone(dst));
+            (**self).deep_clone(ctx)
+        });
+        ctx.finish_copy(key, copy.clone(dst));
+        copy
+    }
+}
+
+impl<T: DeepClone + 'static, A: Allocator + Clone + 'static> DeepClone for RootedWeak<T, A> {
+    /// Refers to the copy of the allocation, cloning it first if it's still
+    /// alive and hasn't been reached yet.
+    fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+        let src = ctx.src_root();
+        let dst = ctx.dst_root();
+        check_root(src, self.tag());
+        let key = self.internal.as_ptr() as *const ();
+        if let Some(weak) = ctx.copy_of::<T, A>(key) {
+            return weak;
+        }
+        if let Some(strong) = self.upgrade(src) {
+            let copy = strong.deep_clone(ctx);
+            let weak = RootedRc::downgrade(&copy, dst);
+            copy.safely_drop(dst);
+            strong.safely_drop(src);
+            return weak;
+        }
+        // The value is gone, so the copy is a block that never gets one,
+        // shared with any other weak references to the same allocation.
+        // SAFETY: The block is live as long as `self` is.
+        let alloc = unsafe { &*addr_of!((*self.internal.as_ptr()).alloc) }.clone();
+        let weak = RootedWeak {
+            internal: RootedRcInternal::<T, A>::allocate(dst, alloc, 0),
+        };
+        ctx.start_copy(key, weak.clone(dst));
+        weak
     }
 }
 
@@ -137,19 +581,112 @@ impl<T> Drop for RootedRc<T> {
 // `Sync`. However, RootedRc ensures that `Rc`'s reference count can only be
 // accessed when the root is locked by the current thread, effectively
 // synchronizing the reference count.
-unsafe impl<T: Sync + Send> Send for RootedRc<T> {}
-unsafe impl<T: Sync + Send> Sync for RootedRc<T> {}
+//
+// As for `Arc`, the allocator may be used to free the block from whichever
+// thread drops the last reference, so it must be `Send`. It's also shared
+// through `allocator` by references on different threads, so it must be
+// `Sync` too.
+unsafe impl<T: Sync + Send, A: Allocator + Send + Sync> Send for RootedRc<T, A> {}
+unsafe impl<T: Sync + Send, A: Allocator + Send + Sync> Sync for RootedRc<T, A> {}
 
-impl<T> std::ops::Deref for RootedRc<T> {
+impl<T, A: Allocator> core::ops::Deref for RootedRc<T, A> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        &unsafe { self.internal.as_ref() }.unwrap().val
+        &unsafe { self.internal.as_ptr().as_ref() }.unwrap().val
     }
 }
 
+/// Analagous to `std::rc::Weak`: a reference to a `RootedRc` allocation that
+/// doesn't keep the value alive. Created by `RootedRc::downgrade` or
+/// `RootedRc::new_cyclic`.
+///
+/// As for `RootedRc`, instances must be destroyed using `safely_drop`.
+pub struct RootedWeak<T, A: Allocator = Global> {
+    internal: NonNull<RootedRcInternal<T, A>>,
+}
+
+impl<T, A: Allocator> RootedWeak<T, A> {
+    /// Returns a strong reference to the object, or `None` if its value has
+    /// already been dropped.
+    ///
+    /// Panics if `root` is for the wrong `Root`.
+    pub fn upgrade<P: RootProof + ?Sized>(&self, root: &P) -> Option<RootedRc<T, A>> {
+        let root = root.root();
+        check_root(root, self.tag());
+        // SAFETY: The block is live as long as we are, and we hold the root.
+        let strong = unsafe { RootedRcInternal::strong(self.internal.as_ptr()) };
+        if strong.get() == 0 {
+            return None;
+        }
+        strong.inc();
+        root.stats.rc_clone();
+        Some(RootedRc {
+            internal: self.internal,
+        })
+    }
+
+    /// Like `RootedRc::clone`.
+    ///
+    /// Panics if `root` is for the wrong `Root`.
+    pub fn clone<P: RootProof + ?Sized>(&self, root: &P) -> Self {
+        let root = root.root();
+        check_root(root, self.tag());
+        // SAFETY: The block is live as long as we are, and we hold the root.
+        unsafe { RootedRcInternal::weak(self.internal.as_ptr()) }.inc();
+        Self {
+            internal: self.internal,
+        }
+    }
+
+    /// Like `RootedRc::safely_drop`, freeing the internal block if no other
+    /// references remain.
+    ///
+    /// Panics if `root` is for the wrong `Root`.
+    pub fn safely_drop<P: RootProof + ?Sized>(self, root: &P) {
+        let root = root.root();
+        check_root(root, self.tag());
+        // SAFETY: The block is live as long as we are, we hold the root, and
+        // we don't use the reference again.
+        unsafe { RootedRcInternal::release_weak(self.internal.as_ptr(), root) };
+        core::mem::forget(self);
+    }
+
+    fn tag(&self) -> Tag {
+        // SAFETY: The block is live as long as `self` is, and the tag is
+        // immutable.
+        unsafe { addr_of!((*self.internal.as_ptr()).tag).read() }
+    }
+}
+
+impl<T, A: Allocator> Drop for RootedWeak<T, A> {
+    fn drop(&mut self) {
+        // Only reached if `safely_drop` wasn't called, since it forgets
+        // `self`.
+        log::error!("Dropped without calling `safely_drop`");
+        error_event!(
+            tag = ?self.tag(),
+            ptr = ?self.internal,
+            ty = core::any::type_name::<T>(),
+            "leaked RootedWeak dropped without calling `safely_drop`"
+        );
+
+        // As for `RootedRc`, the block is simply leaked.
+        #[cfg(debug_assertions)]
+        if !crate::panicking() {
+            panic!("Dropped without calling `safely_drop`");
+        }
+    }
+}
+
+// SAFETY: As for `RootedRc`. A weak reference can be upgraded to a strong
+// one, so needs the same bounds.
+unsafe impl<T: Sync + Send, A: Allocator + Send + Sync> Send for RootedWeak<T, A> {}
+unsafe impl<T: Sync + Send, A: Allocator + Send + Sync> Sync for RootedWeak<T, A> {}
+
 #[cfg(test)]
 mod test_rooted_rc {
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::{sync::Arc, thread};
 
     use crate::Root;
@@ -246,4 +783,198 @@ mod test_rooted_rc {
 
         rc.safely_drop(&root.lock().unwrap());
     }
+
+    /// Counts the blocks currently allocated through it.
+    #[derive(Clone, Default)]
+    struct CountingAlloc(Arc<AtomicUsize>);
+
+    unsafe impl Allocator for CountingAlloc {
+        fn allocate(
+            &self,
+            layout: Layout,
+        ) -> Result<NonNull<[u8]>, allocator_api2::alloc::AllocError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+            unsafe { Global.deallocate(ptr, layout) }
+        }
+    }
+
+    #[test]
+    fn new_in_uses_allocator() {
+        let root = Root::new();
+        let alloc = CountingAlloc::default();
+        let rc = RootedRc::new_in(&root, String::from("x"), alloc.clone());
+        let rc2 = rc.clone(&root);
+        assert_eq!(alloc.0.load(Ordering::Relaxed), 1);
+        assert_eq!(*rc2, "x");
+        rc.safely_drop(&root);
+        assert_eq!(alloc.0.load(Ordering::Relaxed), 1);
+        rc2.safely_drop(&root);
+        assert_eq!(alloc.0.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn weak_upgrade_until_dropped() {
+        let root = Root::new();
+        let rc = RootedRc::new(&root, String::from("x"));
+        let weak = RootedRc::downgrade(&rc, &root);
+        let weak2 = weak.clone(&root);
+
+        let upgraded = weak.upgrade(&root).unwrap();
+        assert_eq!(*upgraded, "x");
+        upgraded.safely_drop(&root);
+
+        rc.safely_drop(&root);
+        assert!(weak.upgrade(&root).is_none());
+        weak.safely_drop(&root);
+        weak2.safely_drop(&root);
+    }
+
+    #[test]
+    fn new_cyclic_refers_to_itself() {
+        use crate::refcell::RootedRefCell;
+
+        struct Process {
+            this: RootedRefCell<Option<RootedWeak<Process>>>,
+            pid: u32,
+        }
+
+        let root = Root::new();
+        let process = RootedRc::new_cyclic(&root, |weak| {
+            assert!(weak.upgrade(&root).is_none());
+            Process {
+                this: RootedRefCell::new(&root, Some(weak.clone(&root))),
+                pid: 7,
+            }
+        });
+        let this = process.this.borrow(&root).as_ref().unwrap().upgrade(&root);
+        let this = this.unwrap();
+        assert_eq!(this.pid, 7);
+        this.safely_drop(&root);
+
+        let weak = process.this.borrow_mut(&root).take().unwrap();
+        weak.safely_drop(&root);
+        process.safely_drop(&root);
+    }
+
+    #[test]
+    fn get_mut_requires_unique() {
+        let root = Root::new();
+        let mut rc = RootedRc::new(&root, 1);
+        *RootedRc::get_mut(&mut rc, &root).unwrap() += 1;
+
+        let rc2 = rc.clone(&root);
+        assert!(RootedRc::get_mut(&mut rc, &root).is_none());
+        rc2.safely_drop(&root);
+
+        let weak = RootedRc::downgrade(&rc, &root);
+        assert!(RootedRc::get_mut(&mut rc, &root).is_none());
+        weak.safely_drop(&root);
+
+        assert_eq!(*rc, 2);
+        rc.safely_drop(&root);
+    }
+
+    #[test]
+    fn get_mut_with_shared_cell() {
+        let mut root = Root::new();
+        let rc = RootedRc::new(&root, RootedRefCell::new(&root, 1));
+        let rc2 = rc.clone(&root);
+        *RootedRc::get_mut_with(&rc, &mut root) += 1;
+        *RootedRc::get_mut_with(&rc2, &mut root) += 1;
+        assert_eq!(*rc.borrow(&root), 3);
+        rc.safely_drop(&root);
+        rc2.safely_drop(&root);
+    }
+
+    #[test]
+    fn new_uninit_then_init() {
+        let root = Root::new();
+        let mut rc = RootedRc::<String>::new_uninit(&root);
+        RootedRc::get_mut(&mut rc, &root)
+            .unwrap()
+            .write(String::from("x"));
+        let rc = unsafe { rc.assume_init() };
+        assert_eq!(*rc, "x");
+        rc.safely_drop(&root);
+    }
+
+    #[test]
+    fn pinned_value_stays_put() {
+        let root = Root::new();
+        let pinned = RootedRc::pin(&root, 5);
+        let clone = RootedRc::clone_pinned(&pinned, &root);
+        assert!(core::ptr::eq(&*pinned, &*clone));
+        RootedRc::safely_drop_pinned(pinned, &root);
+        RootedRc::safely_drop_pinned(clone, &root);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let root = Root::new();
+        let rc = RootedRc::new(&root, String::from("x"));
+        let ptr = RootedRc::into_raw(rc);
+        assert_eq!(unsafe { &*ptr }, "x");
+        unsafe {
+            RootedRc::<String>::increment_strong_count(&root, ptr);
+            RootedRc::<String>::decrement_strong_count(&root, ptr);
+        }
+        let rc = unsafe { RootedRc::<String>::from_raw(&root, ptr) };
+        assert_eq!(RootedRc::as_ptr(&rc), ptr);
+        rc.safely_drop(&root);
+    }
+
+    #[test]
+    #[should_panic(expected = "Tried using a lock")]
+    fn from_raw_with_wrong_root_panics() {
+        let root = Root::new();
+        let ptr = RootedRc::into_raw(RootedRc::new(&root, 0));
+        let _ = unsafe { RootedRc::<i32>::from_raw(&Root::new(), ptr) };
+    }
+
+    #[test]
+    fn from_raw_with_wrong_root_keeps_reference() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let root = Root::new();
+        let ptr = RootedRc::into_raw(RootedRc::new(&root, 0));
+        let other = Root::new();
+        assert!(catch_unwind(AssertUnwindSafe(|| unsafe {
+            RootedRc::<i32>::from_raw(&other, ptr)
+        }))
+        .is_err());
+        // No `RootedRc` was built, so none was dropped without `safely_drop`,
+        // and the reference is still held by `ptr`.
+        unsafe { RootedRc::<i32>::decrement_strong_count(&root, ptr) };
+    }
+
+    #[test]
+    fn root_identity() {
+        use crate::refcell::RootedRefCell;
+
+        let root = Root::new();
+        let other_root = Root::new();
+        let rc = RootedRc::new(&root, RootedRefCell::new(&other_root, 0));
+        assert_eq!(RootedRc::root_id(&rc), root.id());
+        assert_ne!(root.id(), other_root.id());
+        assert!(RootedRc::belongs_to(&rc, &root));
+        assert!(!RootedRc::belongs_to(&rc, &other_root));
+        // Methods of the contents aren't shadowed.
+        assert_eq!(rc.root_id(), other_root.id());
+        assert!(rc.belongs_to(&other_root));
+        rc.safely_drop(&root);
+    }
+
+    #[test]
+    fn handles_are_pointer_sized() {
+        use core::mem::size_of;
+
+        assert_eq!(size_of::<RootedRc<u64>>(), size_of::<usize>());
+        assert_eq!(size_of::<Option<RootedRc<u64>>>(), size_of::<usize>());
+        assert_eq!(size_of::<Option<RootedWeak<u64>>>(), size_of::<usize>());
+    }
 }
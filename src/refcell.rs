@@ -1,5 +1,15 @@
-use crate::{Root, Tag};
-use std::cell::{Cell, UnsafeCell};
+use crate::deep_clone::{DeepClone, DeepCloneContext};
+#[cfg(feature = "journal")]
+use crate::journal::Journal;
+#[cfg(feature = "stats")]
+use crate::stats::StatsCell;
+use crate::sync::BorrowState;
+#[cfg(feature = "poison")]
+use crate::sync::Flag;
+use crate::{Root, RootId, RootProof, Tag};
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
 
 /// Analagous to `std::cell::RefCell`. In particular like `RefCell` and unlike
 /// `std::sync::Mutex`, it  doesn't perform any atomic operations internally,
@@ -8,11 +18,24 @@ use std::cell::{Cell, UnsafeCell};
 /// Unlike `RefCell`, this type is `Send` and `Sync` if `T` is Send. This is
 /// safe because the owner is required to prove ownership of the associated
 /// `Root` lock to perform any sensitive operations.
+///
+/// With the `poison` feature, a panic while the cell is mutably borrowed
+/// poisons it, as for `std::sync::Mutex`. Other borrows of a poisoned cell
+/// then fail with `BorrowError::Poisoned`, or panic for `borrow` and
+/// `borrow_mut`, except for `borrow_checked` and `borrow_mut_checked`, which
+/// return a `PoisonError` that still gives access to the possibly
+/// half-updated value.
+///
+/// With the `journal` feature, while the root has an active checkpoint,
+/// the cell can only be mutably borrowed through
+/// `RootedRc::borrow_mut_journaled`, so that the mutation can be rolled back.
+/// Other mutable borrows fail with `BorrowError::CheckpointActive`.
 pub struct RootedRefCell<T> {
     tag: Tag,
+    borrow: BorrowState,
+    #[cfg(feature = "poison")]
+    poisoned: Flag,
     val: UnsafeCell<T>,
-    reader_count: Cell<u32>,
-    writer: Cell<bool>,
 }
 
 impl<T> RootedRefCell<T> {
@@ -20,56 +43,365 @@ impl<T> RootedRefCell<T> {
     pub fn new(root: &Root, val: T) -> Self {
         Self {
             tag: root.tag(),
+            borrow: BorrowState::new(),
+            #[cfg(feature = "poison")]
+            poisoned: Flag::new(false),
             val: UnsafeCell::new(val),
-            reader_count: Cell::new(0),
-            writer: Cell::new(false),
         }
     }
 
     /// Borrow a reference. Panics if `root_guard` is for the wrong `Root`, or
     /// if this object is alread mutably borrowed.
-    pub fn borrow<'a>(
+    pub fn borrow<'a, P: RootProof + ?Sized>(
         &'a self,
         // This 'a statically enforces that the root lock can't be dropped
         // while the returned guard is still outstanding. i.e. it is part
         // of the safety proof for making Self Send and Sync.
-        root: &'a Root,
+        root: &'a P,
     ) -> RootedRefCellRef<'a, T> {
+        let root = root.root();
+        match self.try_borrow(root) {
+            Ok(guard) => guard,
+            Err(e) => self.borrow_failed(root, e),
+        }
+    }
+
+    /// Like `borrow`, but returns an error instead of panicking if `root` is
+    /// for the wrong `Root`, if this object is already mutably borrowed, or
+    /// if it's poisoned.
+    ///
+    /// Still panics if the number of outstanding borrows would overflow,
+    /// since that can only happen if guards are being leaked.
+    pub fn try_borrow<'a, P: RootProof + ?Sized>(
+        &'a self,
+        // 'a required here for safety, as for `borrow`.
+        root: &'a P,
+    ) -> Result<RootedRefCellRef<'a, T>, BorrowError> {
+        let root = root.root();
         // Prove that the lock is held for this tag.
-        assert_eq!(
-            root.tag, self.tag,
-            "Expected {:?} Got {:?}",
-            self.tag, root.tag
-        );
+        self.check_root(root)?;
+        #[cfg(feature = "poison")]
+        self.check_poison()?;
 
-        assert!(!self.writer.get());
+        self.acquire(root)
+    }
+
+    /// Takes a shared borrow, once the caller has checked `root`.
+    fn acquire<'a>(&'a self, root: &'a Root) -> Result<RootedRefCellRef<'a, T>, BorrowError> {
+        let _readers = match self.borrow.try_add_reader() {
+            Ok(readers) => readers,
+            Err(BorrowState::WRITING) => return Err(BorrowError::MutablyBorrowed),
+            Err(_) => panic!("too many outstanding RootedRefCell borrows"),
+        };
+        trace_event!(
+            tag = ?self.tag,
+            readers = _readers,
+            "borrowed RootedRefCell"
+        );
 
-        self.reader_count.set(self.reader_count.get() + 1);
+        root.stats.refcell_borrow();
 
         // Borrow from the guard to ensure the lock can't be dropped.
-        RootedRefCellRef { guard: self }
+        Ok(RootedRefCellRef {
+            guard: self,
+            #[cfg(feature = "stats")]
+            stats: &root.stats,
+            #[cfg(feature = "journal")]
+            journal: &root.journal,
+            _root: PhantomData,
+        })
     }
 
     /// Borrow a mutable reference. Panics if `root_guard` is for the wrong
-    /// `Root`, or if this object is already borrowed.
-    pub fn borrow_mut<'a>(
+    /// `Root`, if this object is already borrowed, or if `root` has an active
+    /// checkpoint.
+    pub fn borrow_mut<'a, P: RootProof + ?Sized>(
         &'a self,
         // 'a required here for safety, as for `borrow`.
-        root: &'a Root,
+        root: &'a P,
     ) -> RootedRefCellRefMut<'a, T> {
+        let root = root.root();
+        match self.try_borrow_mut(root) {
+            Ok(guard) => guard,
+            Err(e) => self.borrow_failed(root, e),
+        }
+    }
+
+    /// Like `borrow_mut`, but returns an error instead of panicking if `root`
+    /// is for the wrong `Root`, if this object is already borrowed or
+    /// poisoned, or if `root` has an active checkpoint.
+    pub fn try_borrow_mut<'a, P: RootProof + ?Sized>(
+        &'a self,
+        // 'a required here for safety, as for `borrow`.
+        root: &'a P,
+    ) -> Result<RootedRefCellRefMut<'a, T>, BorrowError> {
+        let root = root.root();
         // Prove that the lock is held for this tag.
-        assert_eq!(
-            root.tag, self.tag,
-            "Expected {:?} Got {:?}",
-            self.tag, root.tag
-        );
+        self.check_root(root)?;
+        #[cfg(feature = "poison")]
+        self.check_poison()?;
+        #[cfg(feature = "journal")]
+        root.journal.check_unjournaled()?;
+
+        self.acquire_mut(root)
+    }
+
+    /// Like `borrow_mut`, but allowed while `root` has an active checkpoint,
+    /// for mutations that the journal records or is itself undoing.
+    #[cfg(feature = "journal")]
+    pub(crate) fn borrow_mut_for_journal<'a>(
+        &'a self,
+        root: &'a Root,
+    ) -> RootedRefCellRefMut<'a, T> {
+        if let Err(e) = self.check_root(root) {
+            self.borrow_failed(root, e);
+        }
+        #[cfg(feature = "poison")]
+        if let Err(e) = self.check_poison() {
+            self.borrow_failed(root, e);
+        }
+        match self.acquire_mut(root) {
+            Ok(guard) => guard,
+            Err(e) => self.borrow_failed(root, e),
+        }
+    }
+
+    /// Takes a mutable borrow, once the caller has checked `root`.
+    fn acquire_mut<'a>(
+        &'a self,
+        root: &'a Root,
+    ) -> Result<RootedRefCellRefMut<'a, T>, BorrowError> {
+        match self.borrow.try_set_writing(BorrowState::UNUSED) {
+            Ok(()) => (),
+            Err(BorrowState::WRITING) => return Err(BorrowError::MutablyBorrowed),
+            Err(_) => return Err(BorrowError::Borrowed),
+        }
+        trace_event!(tag = ?self.tag, "mutably borrowed RootedRefCell");
+
+        root.stats.refcell_borrow_mut();
+
+        Ok(RootedRefCellRefMut {
+            guard: self,
+            #[cfg(feature = "stats")]
+            stats: &root.stats,
+            #[cfg(feature = "journal")]
+            journal: &root.journal,
+            #[cfg(feature = "poison")]
+            panicking: std::thread::panicking(),
+            _root: PhantomData,
+        })
+    }
 
-        assert!(!self.writer.get());
-        assert!(self.reader_count.get() == 0);
+    /// Returns a mutable reference to the value, without updating the borrow
+    /// state. Panics if `root` is for the wrong `Root`, or if it has an active
+    /// checkpoint.
+    ///
+    /// Every guard borrows the `Root` it was created from, so holding it
+    /// mutably proves that no guards for this cell are outstanding, and that
+    /// none can be created until the returned reference is dropped. This
+    /// also covers cells shared through a `RootedRc`, which derefs to the
+    /// cell.
+    pub fn get_mut_with<'a>(&'a self, root: &'a mut Root) -> &'a mut T {
+        if let Err(e) = self.check_root(root) {
+            self.borrow_failed(root, e);
+        }
+        #[cfg(feature = "poison")]
+        if let Err(e) = self.check_poison() {
+            self.borrow_failed(root, e);
+        }
+        #[cfg(feature = "journal")]
+        if let Err(e) = root.journal.check_unjournaled() {
+            self.borrow_failed(root, e);
+        }
+        // SAFETY: As above, `root` is exclusively borrowed for `'a`, and only
+        // one thread can hold it.
+        unsafe { &mut *self.val.get() }
+    }
 
-        self.writer.set(true);
+    /// Like `borrow`, but if the cell is poisoned, returns the guard wrapped
+    /// in a `PoisonError` instead of panicking.
+    #[cfg(feature = "poison")]
+    pub fn borrow_checked<'a, P: RootProof + ?Sized>(
+        &'a self,
+        // 'a required here for safety, as for `borrow`.
+        root: &'a P,
+    ) -> Result<RootedRefCellRef<'a, T>, PoisonError<RootedRefCellRef<'a, T>>> {
+        let root = root.root();
+        if let Err(e) = self.check_root(root) {
+            self.borrow_failed(root, e);
+        }
+        let guard = match self.acquire(root) {
+            Ok(guard) => guard,
+            Err(e) => self.borrow_failed(root, e),
+        };
+        if self.poisoned.get() {
+            Err(PoisonError { guard })
+        } else {
+            Ok(guard)
+        }
+    }
 
-        RootedRefCellRefMut { guard: self }
+    /// Like `borrow_mut`, but if the cell is poisoned, returns the guard
+    /// wrapped in a `PoisonError` instead of panicking. The cell stays
+    /// poisoned until `clear_poison` is called.
+    #[cfg(feature = "poison")]
+    pub fn borrow_mut_checked<'a, P: RootProof + ?Sized>(
+        &'a self,
+        // 'a required here for safety, as for `borrow`.
+        root: &'a P,
+    ) -> Result<RootedRefCellRefMut<'a, T>, PoisonError<RootedRefCellRefMut<'a, T>>> {
+        let root = root.root();
+        if let Err(e) = self.check_root(root) {
+            self.borrow_failed(root, e);
+        }
+        #[cfg(feature = "journal")]
+        if let Err(e) = root.journal.check_unjournaled() {
+            self.borrow_failed(root, e);
+        }
+        let guard = match self.acquire_mut(root) {
+            Ok(guard) => guard,
+            Err(e) => self.borrow_failed(root, e),
+        };
+        if self.poisoned.get() {
+            Err(PoisonError { guard })
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Whether a panic happened while the cell was mutably borrowed.
+    #[cfg(feature = "poison")]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.get()
+    }
+
+    /// Marks the cell as no longer poisoned, once the caller has checked or
+    /// repaired its contents. Panics if `root` is for the wrong `Root`.
+    #[cfg(feature = "poison")]
+    pub fn clear_poison<P: RootProof + ?Sized>(&self, root: &P) {
+        let root = root.root();
+        if let Err(e) = self.check_root(root) {
+            self.borrow_failed(root, e);
+        }
+        self.poisoned.set(false);
+    }
+
+    #[cfg(feature = "poison")]
+    fn check_poison(&self) -> Result<(), BorrowError> {
+        if self.poisoned.get() {
+            error_event!(tag = ?self.tag, "borrowed poisoned RootedRefCell");
+            return Err(BorrowError::Poisoned);
+        }
+        Ok(())
+    }
+
+    /// The identifier of the `Root` this cell is associated with.
+    pub fn root_id(&self) -> RootId {
+        RootId(self.tag)
+    }
+
+    /// Whether this cell is associated with `root`, i.e. whether borrowing
+    /// it with `root` wouldn't fail with `BorrowError::WrongRoot`.
+    pub fn belongs_to(&self, root: &Root) -> bool {
+        root.tag == self.tag
+    }
+
+    /// Checks that `try_borrow_mut` would succeed, as part of a call to
+    /// `borrow_many_mut` in which this is the cell at `index`. `cells` holds
+    /// the addresses of all the cells being borrowed, so that borrowing the
+    /// same cell twice is reported as a conflict.
+    fn check_borrow_many_mut(
+        &self,
+        root: &Root,
+        index: usize,
+        cells: &[*const ()],
+    ) -> Result<(), BorrowError> {
+        self.check_root(root)?;
+        #[cfg(feature = "poison")]
+        self.check_poison()?;
+        #[cfg(feature = "journal")]
+        root.journal.check_unjournaled()?;
+
+        if cells[..index].contains(&(self as *const Self as *const ())) {
+            return Err(BorrowError::MutablyBorrowed);
+        }
+        match self.borrow.get() {
+            BorrowState::UNUSED => Ok(()),
+            BorrowState::WRITING => Err(BorrowError::MutablyBorrowed),
+            _ => Err(BorrowError::Borrowed),
+        }
+    }
+
+    fn check_root(&self, root: &Root) -> Result<(), BorrowError> {
+        if root.tag != self.tag {
+            error_event!(
+                root = ?root.tag,
+                expected = ?self.tag,
+                "borrowed RootedRefCell with wrong root"
+            );
+            return Err(BorrowError::WrongRoot);
+        }
+        Ok(())
+    }
+
+    /// Reconstructs a guard for a borrow whose guard was leaked with
+    /// `std::mem::forget`, so that dropping it releases the borrow. Returns
+    /// `None` if the object isn't immutably borrowed.
+    ///
+    /// # Safety
+    ///
+    /// The leaked guard must have been created from `root`, and no other guard
+    /// may be reconstructed for it.
+    #[cfg(feature = "ffi")]
+    pub(crate) unsafe fn unleak_borrow<'a>(
+        &'a self,
+        root: &'a Root,
+    ) -> Option<RootedRefCellRef<'a, T>> {
+        if root.tag != self.tag || self.borrow.get() <= 0 {
+            return None;
+        }
+        Some(RootedRefCellRef {
+            guard: self,
+            #[cfg(feature = "stats")]
+            stats: &root.stats,
+            #[cfg(feature = "journal")]
+            journal: &root.journal,
+            _root: PhantomData,
+        })
+    }
+
+    /// Like `unleak_borrow`, for mutable borrows.
+    ///
+    /// # Safety
+    ///
+    /// As for `unleak_borrow`.
+    #[cfg(feature = "ffi")]
+    pub(crate) unsafe fn unleak_borrow_mut<'a>(
+        &'a self,
+        root: &'a Root,
+    ) -> Option<RootedRefCellRefMut<'a, T>> {
+        if root.tag != self.tag || self.borrow.get() != BorrowState::WRITING {
+            return None;
+        }
+        Some(RootedRefCellRefMut {
+            guard: self,
+            #[cfg(feature = "stats")]
+            stats: &root.stats,
+            #[cfg(feature = "journal")]
+            journal: &root.journal,
+            #[cfg(feature = "poison")]
+            panicking: std::thread::panicking(),
+            _root: PhantomData,
+        })
+    }
+
+    #[cold]
+    #[track_caller]
+    fn borrow_failed(&self, root: &Root, err: BorrowError) -> ! {
+        match err {
+            BorrowError::WrongRoot => panic!("Expected {:?} Got {:?}", self.tag, root.tag),
+            err => panic!("{}", err),
+        }
     }
 
     pub fn into_inner(self) -> T {
@@ -77,14 +409,220 @@ impl<T> RootedRefCell<T> {
     }
 }
 
+impl<T: DeepClone> DeepClone for RootedRefCell<T> {
+    /// Borrows the contents under the source root, and copies them into a new
+    /// cell associated with the destination root. Panics if the cell is
+    /// already mutably borrowed.
+    fn deep_clone(&self, ctx: &mut DeepCloneContext) -> Self {
+        let val = self.borrow(ctx.src_root()).deep_clone(ctx);
+        RootedRefCell::new(ctx.dst_root(), val)
+    }
+}
+
+/// Error returned by `RootedRefCell::try_borrow` and
+/// `RootedRefCell::try_borrow_mut`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BorrowError {
+    /// The `Root` isn't the one the cell is associated with.
+    WrongRoot,
+    /// The cell is already mutably borrowed.
+    MutablyBorrowed,
+    /// The cell is already immutably borrowed.
+    Borrowed,
+    /// The root has an active checkpoint, so the cell can only be mutably
+    /// borrowed through `RootedRc::borrow_mut_journaled`. Only returned with
+    /// the `journal` feature.
+    CheckpointActive,
+    /// A panic happened while the cell was mutably borrowed. Only returned
+    /// with the `poison` feature; see `RootedRefCell::borrow_checked`.
+    Poisoned,
+}
+
+impl core::fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BorrowError::WrongRoot => write!(f, "wrong root"),
+            BorrowError::MutablyBorrowed => write!(f, "already mutably borrowed"),
+            BorrowError::Borrowed => write!(f, "already borrowed"),
+            BorrowError::CheckpointActive => {
+                write!(f, "unjournaled mutable borrow while a checkpoint is active")
+            }
+            BorrowError::Poisoned => {
+                write!(
+                    f,
+                    "RootedRefCell poisoned by a panic during a mutable borrow"
+                )
+            }
+        }
+    }
+}
+
+impl core::error::Error for BorrowError {}
+
+/// Error returned by `RootedRefCell::borrow_checked` and
+/// `RootedRefCell::borrow_mut_checked` if the cell is poisoned. The borrow
+/// still succeeded, and its guard can be recovered with `into_inner`.
+#[cfg(feature = "poison")]
+pub struct PoisonError<G> {
+    guard: G,
+}
+
+#[cfg(feature = "poison")]
+impl<G> PoisonError<G> {
+    /// Returns the guard, giving access to the possibly inconsistent value.
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+#[cfg(feature = "poison")]
+impl<G> core::fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PoisonError").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "poison")]
+impl<G> core::fmt::Display for PoisonError<G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "poisoned RootedRefCell")
+    }
+}
+
+#[cfg(feature = "poison")]
+impl<G> core::error::Error for PoisonError<G> {}
+
+/// Mutably borrows several cells at once. `cells` is either an array of
+/// references to cells of the same type, or a tuple of references to cells of
+/// different types, and the guards are returned in the same shape.
+///
+/// Every cell is checked before any is borrowed, so either all the borrows
+/// succeed or none are taken, and the error lists every cell that couldn't be
+/// borrowed. Passing the same cell twice is reported as a conflict at the
+/// second position.
+pub fn borrow_many_mut<'a, C: BorrowManyMut<'a>, P: RootProof + ?Sized>(
+    // 'a required here for safety, as for `RootedRefCell::borrow`.
+    root: &'a P,
+    cells: C,
+) -> Result<C::Guards, BorrowManyError> {
+    cells.borrow_many_mut(root.root())
+}
+
+/// Sets of cells that can be passed to `borrow_many_mut`.
+pub trait BorrowManyMut<'a> {
+    type Guards;
+
+    fn borrow_many_mut(self, root: &'a Root) -> Result<Self::Guards, BorrowManyError>;
+}
+
+impl<'a, T, const N: usize> BorrowManyMut<'a> for [&'a RootedRefCell<T>; N] {
+    type Guards = [RootedRefCellRefMut<'a, T>; N];
+
+    fn borrow_many_mut(self, root: &'a Root) -> Result<Self::Guards, BorrowManyError> {
+        let addrs = self.map(|cell| cell as *const RootedRefCell<T> as *const ());
+        let mut conflicts = Vec::new();
+        for (i, cell) in self.iter().enumerate() {
+            if let Err(e) = cell.check_borrow_many_mut(root, i, &addrs) {
+                conflicts.push((i, e));
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(BorrowManyError { conflicts });
+        }
+        Ok(self.map(|cell| match cell.acquire_mut(root) {
+            Ok(guard) => guard,
+            Err(e) => cell.borrow_failed(root, e),
+        }))
+    }
+}
+
+macro_rules! impl_borrow_many_mut_for_tuple {
+    ($($name:ident),*) => {
+        impl<'a, $($name),*> BorrowManyMut<'a> for ($(&'a RootedRefCell<$name>,)*) {
+            type Guards = ($(RootedRefCellRefMut<'a, $name>,)*);
+
+            #[allow(non_snake_case)]
+            fn borrow_many_mut(self, root: &'a Root) -> Result<Self::Guards, BorrowManyError> {
+                let ($($name,)*) = self;
+                let addrs = [$($name as *const RootedRefCell<_> as *const (),)*];
+                let mut conflicts = Vec::new();
+                let mut i = 0;
+                $(
+                    if let Err(e) = $name.check_borrow_many_mut(root, i, &addrs) {
+                        conflicts.push((i, e));
+                    }
+                    i += 1;
+                )*
+                let _ = i;
+                if !conflicts.is_empty() {
+                    return Err(BorrowManyError { conflicts });
+                }
+                Ok(($(
+                    match $name.acquire_mut(root) {
+                        Ok(guard) => guard,
+                        Err(e) => $name.borrow_failed(root, e),
+                    },
+                )*))
+            }
+        }
+    };
+}
+impl_borrow_many_mut_for_tuple!(A);
+impl_borrow_many_mut_for_tuple!(A, B);
+impl_borrow_many_mut_for_tuple!(A, B, C);
+impl_borrow_many_mut_for_tuple!(A, B, C, D);
+
+/// Error returned by `borrow_many_mut`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BorrowManyError {
+    conflicts: Vec<(usize, BorrowError)>,
+}
+
+impl BorrowManyError {
+    /// The position of each cell that couldn't be borrowed, in increasing
+    /// order, and why.
+    pub fn conflicts(&self) -> &[(usize, BorrowError)] {
+        &self.conflicts
+    }
+}
+
+impl core::fmt::Display for BorrowManyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "couldn't borrow cells:")?;
+        for (i, (index, err)) in self.conflicts.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{sep}{index} ({err})")?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for BorrowManyError {}
+
 unsafe impl<T: Send> Send for RootedRefCell<T> {}
 unsafe impl<T: Send> Sync for RootedRefCell<T> {}
 
 pub struct RootedRefCellRef<'a, T> {
     guard: &'a RootedRefCell<T>,
+    #[cfg(feature = "stats")]
+    stats: &'a StatsCell,
+    #[cfg(feature = "journal")]
+    journal: &'a Journal,
+    // Guards must be `!Send` and `!Sync`, like the `Root` they borrow.
+    // Otherwise another thread could release the borrow while the thread
+    // holding the root accesses the same cell.
+    _root: PhantomData<&'a Root>,
 }
 
-impl<'a, T> std::ops::Deref for RootedRefCellRef<'a, T> {
+impl<'a, T> core::ops::Deref for RootedRefCellRef<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -92,19 +630,92 @@ impl<'a, T> std::ops::Deref for RootedRefCellRef<'a, T> {
     }
 }
 
+impl<'a, T> RootedRefCellRef<'a, T> {
+    /// Adds another shared borrow of the same cell. Like `Ref::clone`, this
+    /// is an associated function so that it doesn't shadow a method of `T`.
+    #[allow(clippy::should_implement_trait)]
+    pub fn clone(orig: &Self) -> Self {
+        let guard = orig.guard;
+        // Can't be mutably borrowed while `orig` is outstanding.
+        let Ok(_readers) = guard.borrow.try_add_reader() else {
+            panic!("too many outstanding RootedRefCell borrows");
+        };
+        trace_event!(
+            tag = ?guard.tag,
+            readers = _readers,
+            "borrowed RootedRefCell"
+        );
+        #[cfg(feature = "stats")]
+        orig.stats.refcell_borrow();
+        RootedRefCellRef {
+            guard,
+            #[cfg(feature = "stats")]
+            stats: orig.stats,
+            #[cfg(feature = "journal")]
+            journal: orig.journal,
+            _root: PhantomData,
+        }
+    }
+
+    /// Converts this into a mutable borrow without releasing the cell, if
+    /// it's the only outstanding borrow. Otherwise, or if the root has an
+    /// active checkpoint, returns the original guard.
+    pub fn try_upgrade(this: Self) -> Result<RootedRefCellRefMut<'a, T>, Self> {
+        let guard = this.guard;
+        #[cfg(feature = "journal")]
+        if this.journal.check_unjournaled().is_err() {
+            return Err(this);
+        }
+        if guard.borrow.try_set_writing(1).is_err() {
+            return Err(this);
+        }
+        trace_event!(tag = ?guard.tag, "upgraded RootedRefCell borrow");
+        let upgraded = RootedRefCellRefMut {
+            guard,
+            #[cfg(feature = "stats")]
+            stats: this.stats,
+            #[cfg(feature = "journal")]
+            journal: this.journal,
+            #[cfg(feature = "poison")]
+            panicking: std::thread::panicking(),
+            _root: PhantomData,
+        };
+        // The borrow now belongs to `upgraded`.
+        core::mem::forget(this);
+        Ok(upgraded)
+    }
+}
+
 impl<'a, T> Drop for RootedRefCellRef<'a, T> {
     fn drop(&mut self) {
-        self.guard
-            .reader_count
-            .set(self.guard.reader_count.get() - 1);
+        let _readers = self.guard.borrow.remove_reader();
+        trace_event!(
+            tag = ?self.guard.tag,
+            readers = _readers,
+            "released RootedRefCell borrow"
+        );
+        #[cfg(feature = "stats")]
+        self.stats.refcell_release();
     }
 }
 
 pub struct RootedRefCellRefMut<'a, T> {
     guard: &'a RootedRefCell<T>,
+    #[cfg(feature = "stats")]
+    stats: &'a StatsCell,
+    #[cfg(feature = "journal")]
+    journal: &'a Journal,
+    // Whether the thread was already panicking when the borrow was taken, in
+    // which case dropping the guard during that panic doesn't poison the cell.
+    #[cfg(feature = "poison")]
+    panicking: bool,
+    // Guards must be `!Send` and `!Sync`, like the `Root` they borrow.
+    // Otherwise another thread could release the borrow while the thread
+    // holding the root accesses the same cell.
+    _root: PhantomData<&'a Root>,
 }
 
-impl<'a, T> std::ops::Deref for RootedRefCellRefMut<'a, T> {
+impl<'a, T> core::ops::Deref for RootedRefCellRefMut<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -112,15 +723,46 @@ impl<'a, T> std::ops::Deref for RootedRefCellRefMut<'a, T> {
     }
 }
 
-impl<'a, T> std::ops::DerefMut for RootedRefCellRefMut<'a, T> {
+impl<'a, T> core::ops::DerefMut for RootedRefCellRefMut<'a, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { self.guard.val.get().as_mut().unwrap() }
     }
 }
 
+impl<'a, T> RootedRefCellRefMut<'a, T> {
+    /// Converts this into a shared borrow without releasing the cell, so that
+    /// further shared borrows can be taken. Like `RefMut::map`, this is an
+    /// associated function so that it doesn't shadow a method of `T`.
+    pub fn downgrade(this: Self) -> RootedRefCellRef<'a, T> {
+        let guard = this.guard;
+        guard.borrow.downgrade();
+        trace_event!(tag = ?guard.tag, "downgraded RootedRefCell mutable borrow");
+        let downgraded = RootedRefCellRef {
+            guard,
+            #[cfg(feature = "stats")]
+            stats: this.stats,
+            #[cfg(feature = "journal")]
+            journal: this.journal,
+            _root: PhantomData,
+        };
+        // The borrow now belongs to `downgraded`. Mutation is over, so a later
+        // panic can't leave the value half-updated and doesn't poison it.
+        core::mem::forget(this);
+        downgraded
+    }
+}
+
 impl<'a, T> Drop for RootedRefCellRefMut<'a, T> {
     fn drop(&mut self) {
-        self.guard.writer.set(false);
+        #[cfg(feature = "poison")]
+        if !self.panicking && std::thread::panicking() {
+            error_event!(tag = ?self.guard.tag, "poisoned RootedRefCell");
+            self.guard.poisoned.set(true);
+        }
+        self.guard.borrow.clear_writing();
+        trace_event!(tag = ?self.guard.tag, "released RootedRefCell mutable borrow");
+        #[cfg(feature = "stats")]
+        self.stats.refcell_release();
     }
 }
 
@@ -161,4 +803,197 @@ mod test_rooted_refcell {
         drop(borrow);
         rc.safely_drop(&root);
     }
+
+    #[test]
+    fn try_borrow_conflicts() {
+        let root = Root::new();
+        let other_root = Root::new();
+        let cell = RootedRefCell::new(&root, 0);
+
+        assert_eq!(
+            cell.try_borrow(&other_root).err(),
+            Some(BorrowError::WrongRoot)
+        );
+        {
+            let _borrow = cell.try_borrow(&root).unwrap();
+            assert_eq!(
+                cell.try_borrow_mut(&root).err(),
+                Some(BorrowError::Borrowed)
+            );
+            assert!(cell.try_borrow(&root).is_ok());
+        }
+        {
+            let _borrow = cell.try_borrow_mut(&root).unwrap();
+            assert_eq!(
+                cell.try_borrow(&root).err(),
+                Some(BorrowError::MutablyBorrowed)
+            );
+            assert_eq!(
+                cell.try_borrow_mut(&root).err(),
+                Some(BorrowError::MutablyBorrowed)
+            );
+        }
+        assert!(cell.try_borrow_mut(&root).is_ok());
+    }
+
+    // The poison flag is an extra field.
+    #[cfg(not(feature = "poison"))]
+    #[test]
+    fn borrow_state_is_one_word() {
+        assert_eq!(
+            core::mem::size_of::<RootedRefCell<u32>>(),
+            core::mem::size_of::<Tag>() + 2 * core::mem::size_of::<u32>()
+        );
+    }
+
+    #[test]
+    fn borrows_are_counted() {
+        let root = Root::new();
+        let cell = RootedRefCell::new(&root, 0);
+        let a = cell.borrow(&root);
+        let b = cell.borrow(&root);
+        drop(a);
+        assert_eq!(
+            cell.try_borrow_mut(&root).err(),
+            Some(BorrowError::Borrowed)
+        );
+        drop(b);
+        assert!(cell.try_borrow_mut(&root).is_ok());
+    }
+
+    #[test]
+    fn get_mut_with_exclusive_root() {
+        let mut root = Root::new();
+        let rc = RootedRc::new(&root, RootedRefCell::new(&root, 0));
+        let rc2 = rc.clone(&root);
+        *rc.get_mut_with(&mut root) += 1;
+        *rc2.get_mut_with(&mut root) += 1;
+        assert_eq!(*rc.borrow(&root), 2);
+        rc.safely_drop(&root);
+        rc2.safely_drop(&root);
+    }
+
+    #[test]
+    #[should_panic]
+    fn get_mut_with_wrong_root_panics() {
+        let root = Root::new();
+        let mut other_root = Root::new();
+        let cell = RootedRefCell::new(&root, 0);
+        let _ = cell.get_mut_with(&mut other_root);
+    }
+
+    #[cfg(feature = "poison")]
+    #[test]
+    fn panic_during_borrow_mut_poisons() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let root = Root::new();
+        let cell = RootedRefCell::new(&root, 0);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            *cell.borrow_mut(&root) = 1;
+            let _guard = cell.borrow_mut(&root);
+            panic!("half-updated");
+        }));
+        assert!(cell.is_poisoned());
+        assert!(catch_unwind(AssertUnwindSafe(|| drop(cell.borrow(&root)))).is_err());
+        assert_eq!(cell.try_borrow(&root).err(), Some(BorrowError::Poisoned));
+        assert_eq!(
+            cell.try_borrow_mut(&root).err(),
+            Some(BorrowError::Poisoned)
+        );
+        let err = borrow_many_mut(&root, [&cell]).err().unwrap();
+        assert_eq!(err.conflicts(), &[(0, BorrowError::Poisoned)]);
+
+        // Shared borrows don't poison.
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _guard = cell.borrow_checked(&root);
+            panic!("reading");
+        }));
+        assert_eq!(*cell.borrow_checked(&root).err().unwrap().into_inner(), 1);
+        *cell.borrow_mut_checked(&root).err().unwrap().into_inner() = 2;
+
+        cell.clear_poison(&root);
+        assert!(!cell.is_poisoned());
+        assert_eq!(*cell.borrow(&root), 2);
+    }
+
+    #[test]
+    fn convert_guards() {
+        let root = Root::new();
+        let cell = RootedRefCell::new(&root, 0);
+
+        let mut writer = cell.borrow_mut(&root);
+        *writer = 1;
+        let reader = RootedRefCellRefMut::downgrade(writer);
+        let other_reader = cell.borrow(&root);
+        let cloned = RootedRefCellRef::clone(&reader);
+        assert_eq!((*reader, *other_reader, *cloned), (1, 1, 1));
+        drop(other_reader);
+
+        let Err(reader) = RootedRefCellRef::try_upgrade(reader) else {
+            panic!("upgraded with another reader outstanding");
+        };
+        drop(cloned);
+        let mut writer = RootedRefCellRef::try_upgrade(reader).ok().unwrap();
+        *writer = 2;
+        assert_eq!(
+            cell.try_borrow(&root).err(),
+            Some(BorrowError::MutablyBorrowed)
+        );
+        drop(writer);
+        assert_eq!(*cell.borrow(&root), 2);
+        assert!(cell.try_borrow_mut(&root).is_ok());
+    }
+
+    #[test]
+    fn borrow_many_mut_all_or_nothing() {
+        let root = Root::new();
+        let other_root = Root::new();
+        let host = RootedRefCell::new(&root, 0);
+        let process = RootedRefCell::new(&root, String::new());
+        let thread = RootedRefCell::new(&root, 0u8);
+
+        let wrong = RootedRefCell::new(&other_root, 0);
+
+        {
+            let (mut h, mut p, mut t) = borrow_many_mut(&root, (&host, &process, &thread)).unwrap();
+            *h += 1;
+            p.push('x');
+            *t += 1;
+        }
+        let other_host = RootedRefCell::new(&root, 0);
+        {
+            let [mut a, mut b] = borrow_many_mut(&root, [&host, &other_host]).unwrap();
+            core::mem::swap(&mut *a, &mut *b);
+            *a += 1;
+        }
+        assert_eq!(*other_host.borrow(&root), 1);
+
+        let _reader = process.borrow(&root);
+        let err = borrow_many_mut(&root, [&host, &wrong, &host])
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.conflicts(),
+            &[
+                (1, BorrowError::WrongRoot),
+                (2, BorrowError::MutablyBorrowed)
+            ]
+        );
+        let err = borrow_many_mut(&root, (&thread, &process)).err().unwrap();
+        assert_eq!(err.conflicts(), &[(1, BorrowError::Borrowed)]);
+        // Nothing was left borrowed by the failed calls.
+        assert!(host.try_borrow_mut(&root).is_ok());
+        assert!(thread.try_borrow_mut(&root).is_ok());
+        assert_eq!(*host.borrow(&root), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn borrow_with_wrong_root_panics() {
+        let root = Root::new();
+        let other_root = Root::new();
+        let cell = RootedRefCell::new(&root, 0);
+        let _ = cell.borrow(&other_root);
+    }
 }
@@ -0,0 +1,428 @@
+//! Rooted objects that can live in memory shared between processes.
+//!
+//! `RootedRc` points at a `Box` on the process heap, which another process
+//! can't dereference. `ShmemRootedRc` instead allocates its internal block
+//! from a caller-provided `ShmemAllocator`, and refers to it by its offset
+//! from the start of the shared region. Since the region may be mapped at a
+//! different address in each process, every operation that needs to
+//! dereference the block takes the allocator, which knows the region's base
+//! address in the current process.
+//!
+//! `RootedRefCell` contains no pointers, so it can already be placed in shared
+//! memory as-is, provided its contents can.
+//!
+//! Tags are unique across processes (see `Tag::new`), so the usual root checks
+//! still catch an object being accessed with the wrong process's root.
+//!
+//! To operate on the objects, a process needs the `Root` they're associated
+//! with. A `Root` itself lives on the process heap, so it isn't shared
+//! directly; instead it's handed over by identity. The process holding it
+//! gives it up with `Root::into_id`, and passes the resulting `RootId`
+//! (which is `ShmemSafe`) to the next process, which reconstructs it with
+//! `Root::from_id`. As with the root lock within a process, at most one
+//! process may hold the root at a time, and the handover must be synchronized
+//! (e.g. by a lock in the shared region).
+//!
+//! Reference counts use the same `Count` type as `RootedRc`, so the `atomic`
+//! feature and loom's checking apply to them as well.
+
+use core::alloc::Layout;
+use core::cell::Cell;
+use core::marker::PhantomData;
+
+use crate::refcell::RootedRefCell;
+use crate::sync::Count;
+use crate::{Root, RootId, RootProof, Tag};
+
+/// Allocator for a region of shared memory.
+///
+/// # Safety
+///
+/// `alloc` must return offsets from `base` to memory that is valid for
+/// `layout` and not otherwise in use, until passed back to `dealloc`. In every
+/// process sharing the region, `base` must return the address at which that
+/// process has the region mapped. Allocation and deallocation must be safe to
+/// call concurrently from multiple threads and processes.
+pub unsafe trait ShmemAllocator {
+    /// Address of the start of the shared region in the current process.
+    fn base(&self) -> *mut u8;
+
+    /// Allocates memory for `layout`, returning its offset from `base`, or
+    /// `None` if the region is exhausted.
+    fn alloc(&self, layout: Layout) -> Option<usize>;
+
+    /// Frees memory previously returned by `alloc`.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must have been returned by `alloc` on an allocator for the same
+    /// region, with the same `layout`, and not already freed.
+    unsafe fn dealloc(&self, offset: usize, layout: Layout);
+}
+
+/// Marker for types that are meaningful in every process mapping a shared
+/// region, i.e. that contain no pointers to process-local memory.
+///
+/// # Safety
+///
+/// Implementors must not contain absolute pointers, references, or other
+/// process-local state such as file descriptors.
+pub unsafe trait ShmemSafe {}
+
+macro_rules! impl_shmem_safe {
+    ($($t:ty),*) => {
+        $(unsafe impl ShmemSafe for $t {})*
+    };
+}
+impl_shmem_safe!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+unsafe impl<T: ShmemSafe, const N: usize> ShmemSafe for [T; N] {}
+unsafe impl<T: ShmemSafe> ShmemSafe for Option<T> {}
+unsafe impl<T: ShmemSafe> ShmemSafe for Cell<T> {}
+unsafe impl<T: ShmemSafe> ShmemSafe for RootedRefCell<T> {}
+unsafe impl<T: ShmemSafe> ShmemSafe for ShmemRootedRc<T> {}
+unsafe impl ShmemSafe for RootId {}
+
+struct ShmemRcInternal<T> {
+    val: T,
+    strong_count: Count,
+}
+
+/// Offset used to mark a `ShmemRootedRc` that has been safely dropped.
+const DROPPED: usize = usize::MAX;
+
+#[track_caller]
+fn check_root(root: &Root, tag: Tag) {
+    if root.tag != tag {
+        error_event!(root = ?root.tag, expected = ?tag, "used ShmemRootedRc with wrong root");
+        panic!("Tried using a lock for {:?} instead of {:?}", root.tag, tag);
+    }
+}
+
+/// Analagous to `RootedRc`, but allocated from a `ShmemAllocator` and
+/// referring to its internal block by offset, so that it can be shared between
+/// processes that map the same region at different addresses.
+///
+/// As for `RootedRc`, instances must be destroyed using `safely_drop`.
+pub struct ShmemRootedRc<T> {
+    tag: Tag,
+    offset: usize,
+    _phantom: PhantomData<ShmemRcInternal<T>>,
+}
+
+impl<T: ShmemSafe> ShmemRootedRc<T> {
+    /// Creates a new object associated with `root`, allocated from `alloc`.
+    /// Returns `val` back if the allocation fails.
+    pub fn new<A: ShmemAllocator>(root: &Root, alloc: &A, val: T) -> Result<Self, T> {
+        let layout = Layout::new::<ShmemRcInternal<T>>();
+        let Some(offset) = alloc.alloc(layout) else {
+            return Err(val);
+        };
+        let internal = ShmemRcInternal {
+            val,
+            strong_count: Count::new(1),
+        };
+        // SAFETY: `alloc` guarantees the memory is valid for `layout`.
+        unsafe { (alloc.base().add(offset) as *mut ShmemRcInternal<T>).write(internal) };
+        root.live.add_shmem(offset, core::any::type_name::<T>());
+        root.stats.rc_alloc();
+        trace_event!(
+            tag = ?root.tag(),
+            offset,
+            ty = core::any::type_name::<T>(),
+            "allocated ShmemRootedRc"
+        );
+        Ok(Self {
+            tag: root.tag(),
+            offset,
+            _phantom: PhantomData,
+        })
+    }
+}
+
+impl<T> ShmemRootedRc<T> {
+    /// # Safety
+    ///
+    /// `alloc` must be for the region this object was allocated from.
+    unsafe fn internal<'a, A: ShmemAllocator>(&self, alloc: &'a A) -> &'a ShmemRcInternal<T> {
+        debug_assert_ne!(self.offset, DROPPED);
+        // SAFETY: The block is live as long as `self` is, and caller
+        // guarantees `alloc` is for the right region.
+        unsafe { &*(alloc.base().add(self.offset) as *const ShmemRcInternal<T>) }
+    }
+
+    /// Access the enclosed value.
+    ///
+    /// # Safety
+    ///
+    /// `alloc` must be for the region this object was allocated from; i.e. it
+    /// must describe the same shared region as the allocator passed to `new`,
+    /// though possibly mapped at a different address.
+    pub unsafe fn get<'a, A: ShmemAllocator>(&'a self, alloc: &'a A) -> &'a T {
+        // SAFETY: Guaranteed by caller.
+        &unsafe { self.internal(alloc) }.val
+    }
+
+    /// Like `RootedRc::clone`. Panics if `root` is for the wrong `Root`.
+    ///
+    /// # Safety
+    ///
+    /// `alloc` must be for the region this object was allocated from.
+    pub unsafe fn clone<A: ShmemAllocator, P: RootProof + ?Sized>(
+        &self,
+        root: &P,
+        alloc: &A,
+    ) -> Self {
+        let root = root.root();
+        check_root(root, self.tag);
+        // SAFETY: Guaranteed by caller.
+        let internal = unsafe { self.internal(alloc) };
+        // We hold the root, so no other thread or process is manipulating the
+        // count in parallel.
+        let _strong_count = internal.strong_count.inc();
+        root.stats.rc_clone();
+        trace_event!(
+            tag = ?self.tag,
+            offset = self.offset,
+            strong_count = _strong_count,
+            "cloned ShmemRootedRc"
+        );
+        Self {
+            tag: self.tag,
+            offset: self.offset,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Like `RootedRc::safely_drop`, returning the internal block to `alloc`
+    /// if no other references remain. Panics if `root` is for the wrong `Root`.
+    ///
+    /// # Safety
+    ///
+    /// `alloc` must be for the region this object was allocated from.
+    pub unsafe fn safely_drop<A: ShmemAllocator, P: RootProof + ?Sized>(
+        mut self,
+        root: &P,
+        alloc: &A,
+    ) {
+        let root = root.root();
+        check_root(root, self.tag);
+        let drop_internal = {
+            // SAFETY: Guaranteed by caller.
+            let internal = unsafe { self.internal(alloc) };
+            internal.strong_count.dec() == 0
+        };
+        root.stats.rc_safe_drop(drop_internal);
+        if drop_internal {
+            root.live
+                .remove_shmem(self.offset, core::any::type_name::<T>());
+            // SAFETY: There are no remaining references to the block, and we
+            // hold the root so nothing else can be accessing it in parallel.
+            // Caller guarantees `alloc` is for the right region.
+            unsafe {
+                let ptr = alloc.base().add(self.offset) as *mut ShmemRcInternal<T>;
+                core::ptr::drop_in_place(ptr);
+                alloc.dealloc(self.offset, Layout::new::<ShmemRcInternal<T>>());
+            }
+            trace_event!(tag = ?self.tag, offset = self.offset, "freed ShmemRootedRc");
+        }
+        self.offset = DROPPED;
+    }
+}
+
+impl<T> Drop for ShmemRootedRc<T> {
+    fn drop(&mut self) {
+        if self.offset != DROPPED {
+            log::error!("Dropped without calling `safely_drop`");
+            error_event!(
+                tag = ?self.tag,
+                offset = self.offset,
+                ty = core::any::type_name::<T>(),
+                "leaked ShmemRootedRc dropped without calling `safely_drop`"
+            );
+
+            // As for `RootedRc`, the block is simply leaked.
+            #[cfg(debug_assertions)]
+            if !crate::panicking() {
+                panic!("Dropped without calling `safely_drop`");
+            }
+        }
+    }
+}
+
+// SAFETY: As for `RootedRc`, the reference count is only accessed with the
+// root held.
+unsafe impl<T: Sync + Send> Send for ShmemRootedRc<T> {}
+unsafe impl<T: Sync + Send> Sync for ShmemRootedRc<T> {}
+
+#[cfg(test)]
+mod test_shmem_rooted_rc {
+    use std::alloc::Layout;
+    use std::cell::Cell;
+
+    use super::*;
+
+    /// Bump allocator over a fixed buffer, standing in for a shared mapping.
+    struct BumpRegion {
+        buf: Box<[Cell<u64>]>,
+        next: Cell<usize>,
+        live: Cell<usize>,
+    }
+
+    impl BumpRegion {
+        fn new(words: usize) -> Self {
+            Self {
+                buf: (0..words).map(|_| Cell::new(0)).collect(),
+                next: Cell::new(0),
+                live: Cell::new(0),
+            }
+        }
+
+        /// Copies the region to a new address, simulating a second process
+        /// mapping the same memory.
+        fn remap(&self) -> Self {
+            let other = Self::new(self.buf.len());
+            // Copied as raw bytes, since padding within the objects stored in
+            // the region may be uninitialized.
+            unsafe { std::ptr::copy_nonoverlapping(self.base(), other.base(), self.buf.len() * 8) };
+            other.next.set(self.next.get());
+            other.live.set(self.live.get());
+            other
+        }
+    }
+
+    unsafe impl ShmemAllocator for BumpRegion {
+        fn base(&self) -> *mut u8 {
+            self.buf.as_ptr() as *mut u8
+        }
+
+        fn alloc(&self, layout: Layout) -> Option<usize> {
+            assert!(layout.align() <= std::mem::align_of::<u64>());
+            let offset = self.next.get();
+            let end = offset + layout.size().next_multiple_of(8);
+            if end > self.buf.len() * 8 {
+                return None;
+            }
+            self.next.set(end);
+            self.live.set(self.live.get() + 1);
+            Some(offset)
+        }
+
+        unsafe fn dealloc(&self, _offset: usize, _layout: Layout) {
+            self.live.set(self.live.get() - 1);
+        }
+    }
+
+    #[test]
+    fn construct_clone_and_drop() {
+        let root = Root::new();
+        let region = BumpRegion::new(16);
+        let rc = ShmemRootedRc::new(&root, &region, 42u64).ok().unwrap();
+        unsafe {
+            let rc2 = rc.clone(&root, &region);
+            assert_eq!(*rc2.get(&region), 42);
+            rc.safely_drop(&root, &region);
+            assert_eq!(region.live.get(), 1);
+            rc2.safely_drop(&root, &region);
+        }
+        assert_eq!(region.live.get(), 0);
+    }
+
+    #[test]
+    fn alloc_failure_returns_value() {
+        let root = Root::new();
+        let region = BumpRegion::new(0);
+        assert_eq!(ShmemRootedRc::new(&root, &region, 7u64).err(), Some(7));
+    }
+
+    #[test]
+    fn access_from_other_mapping() {
+        let root = Root::new();
+        let region = BumpRegion::new(16);
+        let rc = ShmemRootedRc::new(&root, &region, [1u32, 2, 3])
+            .ok()
+            .unwrap();
+
+        // Store a handle inside the region itself.
+        let outer = ShmemRootedRc::new(
+            &root,
+            &region,
+            RootedRefCell::new(&root, Some(unsafe { rc.clone(&root, &region) })),
+        )
+        .ok()
+        .unwrap();
+
+        let other = region.remap();
+        assert_ne!(region.base(), other.base());
+        unsafe {
+            let inner = outer.get(&other).borrow_mut(&root).take().unwrap();
+            assert_eq!(*inner.get(&other), [1, 2, 3]);
+            inner.safely_drop(&root, &other);
+            outer.safely_drop(&root, &other);
+            rc.safely_drop(&root, &other);
+        }
+        assert_eq!(other.live.get(), 0);
+    }
+
+    #[test]
+    fn hand_root_to_other_process() {
+        let root = Root::new();
+        let region = BumpRegion::new(16);
+        let rc = ShmemRootedRc::new(&root, &region, RootedRefCell::new(&root, 1u32))
+            .ok()
+            .unwrap();
+        let id = ShmemRootedRc::new(&root, &region, root.id()).ok().unwrap();
+        // The id is copied through the region, so this process gives up the
+        // root before the other one takes it.
+        let root_id = unsafe { *id.get(&region) };
+        assert_eq!(root.into_id(), root_id);
+
+        let other = region.remap();
+        let root = unsafe { Root::from_id(root_id) };
+        unsafe {
+            *rc.get(&other).borrow_mut(&root) += 1;
+            assert_eq!(*rc.get(&other).borrow(&root), 2);
+            rc.safely_drop(&root, &other);
+            id.safely_drop(&root, &other);
+        }
+        assert_eq!(other.live.get(), 0);
+    }
+
+    #[cfg(feature = "leak_check")]
+    #[test]
+    fn live_until_released() {
+        let root = Root::new();
+        let region = BumpRegion::new(16);
+        let rc = ShmemRootedRc::new(&root, &region, 0u64).ok().unwrap();
+        let root = root.try_drop().err().unwrap();
+        // Released through another mapping of the region.
+        let other = region.remap();
+        unsafe { rc.safely_drop(&root, &other) };
+        assert!(root.try_drop().is_ok());
+    }
+
+    #[test]
+    #[should_panic]
+    fn drop_without_lock_panics() {
+        let root = Root::new();
+        let region = BumpRegion::new(16);
+        let _ = ShmemRootedRc::new(&root, &region, 0u32);
+    }
+}
@@ -0,0 +1,177 @@
+//! Per-root operation counters, enabled by the `stats` feature.
+//!
+//! Counters are stored in plain `Cell`s inside the `Root`. This is safe for the
+//! same reason the rest of the crate is: every operation that updates them
+//! already has to prove access to the `Root`, and `Root` is `!Sync`.
+//!
+//! Without the `stats` feature the counters are compiled out entirely.
+
+#[cfg(feature = "stats")]
+use core::cell::Cell;
+
+/// Snapshot of the operations performed under a single `Root`, as returned by
+/// `Root::stats`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct RootStats {
+    /// Number of `RootedRc` allocations.
+    pub rc_allocs: u64,
+    /// Number of `RootedRc::clone` calls.
+    pub rc_clones: u64,
+    /// Number of `RootedRc::safely_drop` calls.
+    pub rc_safe_drops: u64,
+    /// Number of `RootedRefCell::borrow` calls.
+    pub refcell_borrows: u64,
+    /// Number of `RootedRefCell::borrow_mut` calls.
+    pub refcell_borrow_muts: u64,
+    /// Number of currently live `RootedRc` allocations.
+    pub live_rcs: u64,
+    /// High-water mark of `live_rcs`.
+    pub max_live_rcs: u64,
+    /// Number of currently outstanding `RootedRefCell` guards.
+    pub outstanding_borrows: u64,
+    /// High-water mark of `outstanding_borrows`.
+    pub max_outstanding_borrows: u64,
+}
+
+/// Counters embedded in each `Root`. Zero-sized when the `stats` feature is
+/// disabled, in which case all methods are no-ops.
+#[derive(Default)]
+pub(crate) struct StatsCell {
+    #[cfg(feature = "stats")]
+    stats: Cell<RootStats>,
+}
+
+impl StatsCell {
+    #[inline]
+    fn update(&self, f: impl FnOnce(&mut RootStats)) {
+        #[cfg(feature = "stats")]
+        {
+            let mut stats = self.stats.get();
+            f(&mut stats);
+            self.stats.set(stats);
+        }
+        #[cfg(not(feature = "stats"))]
+        let _ = f;
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn get(&self) -> RootStats {
+        self.stats.get()
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn reset(&self) {
+        let live_rcs = self.stats.get().live_rcs;
+        let outstanding_borrows = self.stats.get().outstanding_borrows;
+        self.stats.set(RootStats {
+            live_rcs,
+            max_live_rcs: live_rcs,
+            outstanding_borrows,
+            max_outstanding_borrows: outstanding_borrows,
+            ..RootStats::default()
+        })
+    }
+
+    #[inline]
+    pub fn rc_alloc(&self) {
+        self.update(|s| {
+            s.rc_allocs += 1;
+            s.live_rcs += 1;
+            s.max_live_rcs = s.max_live_rcs.max(s.live_rcs);
+        })
+    }
+
+    #[inline]
+    pub fn rc_clone(&self) {
+        self.update(|s| s.rc_clones += 1)
+    }
+
+    #[inline]
+    pub fn rc_safe_drop(&self, freed: bool) {
+        self.update(|s| {
+            s.rc_safe_drops += 1;
+            // Saturating, since a root reconstructed with `Root::from_id`
+            // may free objects allocated before it was handed over.
+            if freed {
+                s.live_rcs = s.live_rcs.saturating_sub(1);
+            }
+        })
+    }
+
+    #[inline]
+    pub fn refcell_borrow(&self) {
+        self.update(|s| {
+            s.refcell_borrows += 1;
+            s.outstanding_borrows += 1;
+            s.max_outstanding_borrows = s.max_outstanding_borrows.max(s.outstanding_borrows);
+        })
+    }
+
+    #[inline]
+    pub fn refcell_borrow_mut(&self) {
+        self.update(|s| {
+            s.refcell_borrow_muts += 1;
+            s.outstanding_borrows += 1;
+            s.max_outstanding_borrows = s.max_outstanding_borrows.max(s.outstanding_borrows);
+        })
+    }
+
+    #[cfg(feature = "stats")]
+    #[inline]
+    pub fn refcell_release(&self) {
+        self.update(|s| s.outstanding_borrows -= 1)
+    }
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod test_stats {
+    use crate::rc::RootedRc;
+    use crate::refcell::RootedRefCell;
+    use crate::Root;
+
+    #[test]
+    fn counts_operations() {
+        let root = Root::new();
+        let rc = RootedRc::new(&root, RootedRefCell::new(&root, 0));
+        let rc2 = rc.clone(&root);
+        {
+            let _b1 = rc.borrow(&root);
+            let _b2 = rc2.borrow(&root);
+        }
+        *rc.borrow_mut(&root) += 1;
+        rc2.safely_drop(&root);
+
+        let stats = root.stats();
+        assert_eq!(stats.rc_allocs, 1);
+        assert_eq!(stats.rc_clones, 1);
+        assert_eq!(stats.rc_safe_drops, 1);
+        assert_eq!(stats.refcell_borrows, 2);
+        assert_eq!(stats.refcell_borrow_muts, 1);
+        assert_eq!(stats.live_rcs, 1);
+        assert_eq!(stats.max_live_rcs, 1);
+        assert_eq!(stats.outstanding_borrows, 0);
+        assert_eq!(stats.max_outstanding_borrows, 2);
+
+        rc.safely_drop(&root);
+        assert_eq!(root.stats().live_rcs, 0);
+    }
+
+    #[test]
+    fn reset_keeps_live_counts() {
+        let root = Root::new();
+        let rc = RootedRc::new(&root, RootedRefCell::new(&root, 0));
+        let borrow = rc.borrow(&root);
+        root.reset_stats();
+
+        let stats = root.stats();
+        assert_eq!(stats.rc_allocs, 0);
+        assert_eq!(stats.refcell_borrows, 0);
+        assert_eq!(stats.live_rcs, 1);
+        assert_eq!(stats.max_live_rcs, 1);
+        assert_eq!(stats.outstanding_borrows, 1);
+        assert_eq!(stats.max_outstanding_borrows, 1);
+
+        drop(borrow);
+        rc.safely_drop(&root);
+    }
+}
@@ -0,0 +1,363 @@
+//! Primitives that the crate's synchronization, and its safety argument,
+//! depend on.
+//!
+//! Under `cfg(loom)` these are replaced by [loom](https://docs.rs/loom)'s
+//! instrumented versions, so that loom can check that reference counts and
+//! borrow flags are never accessed concurrently. See `tests/loom.rs`.
+//!
+//! Reference counts and borrow states are wrapped in `Count` and `BorrowState`. These
+//! are normally plain `Cell`s, relying on the root for synchronization. With
+//! the `atomic` feature they're updated with atomic read-modify-write
+//! operations instead, using the same orderings as `Arc` and `AtomicRefCell`,
+//! so that the cost of those mainstream equivalents can be measured without
+//! changing any call sites. Root checks are performed either way.
+
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+#[cfg(not(loom))]
+pub(crate) use once_cell::race::OnceBox;
+
+#[cfg(all(not(feature = "atomic"), not(loom)))]
+use core::cell::Cell;
+#[cfg(all(not(feature = "atomic"), loom))]
+use loom::cell::Cell;
+
+#[cfg(all(feature = "atomic", feature = "poison", not(loom)))]
+use core::sync::atomic::AtomicBool;
+#[cfg(all(feature = "atomic", not(loom)))]
+use core::sync::atomic::AtomicI32;
+#[cfg(all(feature = "atomic", feature = "poison", loom))]
+use loom::sync::atomic::AtomicBool;
+#[cfg(all(feature = "atomic", loom))]
+use loom::sync::atomic::AtomicI32;
+
+/// Counts above this abort the process, as for `Arc`: they can only be
+/// reached by leaking references (e.g. with `mem::forget`), and letting the
+/// count wrap would free the value while references to it remain.
+pub(crate) const MAX_REFCOUNT: u32 = i32::MAX as u32;
+
+/// Aborts the process because a reference count overflowed. Without `std`
+/// there's no `abort`, so panic while panicking instead, which aborts too.
+#[cold]
+pub(crate) fn refcount_overflow() -> ! {
+    #[cfg(feature = "std")]
+    std::process::abort();
+    #[cfg(not(feature = "std"))]
+    {
+        struct PanicOnDrop;
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                panic!("reference count overflow");
+            }
+        }
+        let _abort = PanicOnDrop;
+        panic!("reference count overflow");
+    }
+}
+
+/// A reference count, or a count of outstanding borrows.
+pub(crate) struct Count {
+    #[cfg(not(feature = "atomic"))]
+    n: Cell<u32>,
+    #[cfg(feature = "atomic")]
+    n: AtomicU32,
+}
+
+#[cfg(not(feature = "atomic"))]
+impl Count {
+    pub fn new(n: u32) -> Self {
+        Self { n: Cell::new(n) }
+    }
+
+    pub fn get(&self) -> u32 {
+        self.n.get()
+    }
+
+    pub fn set(&self, n: u32) {
+        self.n.set(n)
+    }
+
+    /// Increments the count, returning the new value. Aborts if the count
+    /// would exceed `MAX_REFCOUNT`.
+    pub fn inc(&self) -> u32 {
+        let n = self.n.get() + 1;
+        if n > MAX_REFCOUNT {
+            refcount_overflow();
+        }
+        self.n.set(n);
+        n
+    }
+
+    /// Decrements the count, returning the new value.
+    pub fn dec(&self) -> u32 {
+        self.n.set(self.n.get() - 1);
+        self.n.get()
+    }
+}
+
+#[cfg(feature = "atomic")]
+impl Count {
+    pub fn new(n: u32) -> Self {
+        Self {
+            n: AtomicU32::new(n),
+        }
+    }
+
+    pub fn get(&self) -> u32 {
+        self.n.load(Ordering::Acquire)
+    }
+
+    pub fn set(&self, n: u32) {
+        self.n.store(n, Ordering::Release)
+    }
+
+    /// Increments the count, returning the new value. Aborts if the count
+    /// exceeds `MAX_REFCOUNT`.
+    pub fn inc(&self) -> u32 {
+        let old = self.n.fetch_add(1, Ordering::Relaxed);
+        // As for `Arc`, other threads may increment the count further before
+        // we abort, but not by anywhere near enough to wrap it.
+        if old >= MAX_REFCOUNT {
+            refcount_overflow();
+        }
+        old + 1
+    }
+
+    /// Decrements the count, returning the new value.
+    pub fn dec(&self) -> u32 {
+        let n = self.n.fetch_sub(1, Ordering::Release) - 1;
+        if n == 0 {
+            // Synchronizes with the other decrements before the caller frees
+            // whatever the count protects.
+            fence(Ordering::Acquire);
+        }
+        n
+    }
+}
+
+/// The borrow state of a `RootedRefCell`, packed into a single signed
+/// counter as in `std::cell::RefCell`: positive for the number of outstanding
+/// shared borrows, `WRITING` while mutably borrowed, and 0 otherwise.
+pub(crate) struct BorrowState {
+    #[cfg(not(feature = "atomic"))]
+    n: Cell<i32>,
+    #[cfg(feature = "atomic")]
+    n: AtomicI32,
+}
+
+impl BorrowState {
+    pub const UNUSED: i32 = 0;
+    pub const WRITING: i32 = -1;
+    pub const MAX_READERS: i32 = i32::MAX;
+}
+
+#[cfg(not(feature = "atomic"))]
+impl BorrowState {
+    pub fn new() -> Self {
+        Self {
+            n: Cell::new(Self::UNUSED),
+        }
+    }
+
+    pub fn get(&self) -> i32 {
+        self.n.get()
+    }
+
+    /// Adds a shared borrow, returning the new number of readers. Fails,
+    /// returning the previous state, if the cell is mutably borrowed or
+    /// already has `MAX_READERS` readers.
+    pub fn try_add_reader(&self) -> Result<i32, i32> {
+        let n = self.n.get();
+        if n == Self::WRITING || n == Self::MAX_READERS {
+            return Err(n);
+        }
+        self.n.set(n + 1);
+        Ok(n + 1)
+    }
+
+    /// Releases a shared borrow, returning the remaining number of readers.
+    pub fn remove_reader(&self) -> i32 {
+        self.n.set(self.n.get() - 1);
+        self.n.get()
+    }
+
+    /// Marks the cell mutably borrowed if its state is `expected`: `UNUSED`,
+    /// or 1 to upgrade the only shared borrow. Otherwise fails, returning the
+    /// previous state.
+    pub fn try_set_writing(&self, expected: i32) -> Result<(), i32> {
+        let n = self.n.get();
+        if n != expected {
+            return Err(n);
+        }
+        self.n.set(Self::WRITING);
+        Ok(())
+    }
+
+    /// Releases a mutable borrow.
+    pub fn clear_writing(&self) {
+        self.n.set(Self::UNUSED)
+    }
+
+    /// Turns a mutable borrow into a single shared borrow.
+    pub fn downgrade(&self) {
+        self.n.set(1)
+    }
+}
+
+#[cfg(feature = "atomic")]
+impl BorrowState {
+    pub fn new() -> Self {
+        Self {
+            n: AtomicI32::new(Self::UNUSED),
+        }
+    }
+
+    pub fn get(&self) -> i32 {
+        self.n.load(Ordering::Acquire)
+    }
+
+    /// Adds a shared borrow, returning the new number of readers. Fails,
+    /// returning the previous state, if the cell is mutably borrowed or
+    /// already has `MAX_READERS` readers.
+    pub fn try_add_reader(&self) -> Result<i32, i32> {
+        // A single read-modify-write, so the check can't be invalidated
+        // before the update.
+        self.n
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |n| {
+                (n != Self::WRITING && n != Self::MAX_READERS).then_some(n + 1)
+            })
+            .map(|n| n + 1)
+    }
+
+    /// Releases a shared borrow, returning the remaining number of readers.
+    pub fn remove_reader(&self) -> i32 {
+        self.n.fetch_sub(1, Ordering::Release) - 1
+    }
+
+    /// Marks the cell mutably borrowed if its state is `expected`: `UNUSED`,
+    /// or 1 to upgrade the only shared borrow. Otherwise fails, returning the
+    /// previous state.
+    pub fn try_set_writing(&self, expected: i32) -> Result<(), i32> {
+        self.n
+            .compare_exchange(
+                expected,
+                Self::WRITING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .map(|_| ())
+    }
+
+    /// Releases a mutable borrow.
+    pub fn clear_writing(&self) {
+        self.n.store(Self::UNUSED, Ordering::Release)
+    }
+
+    /// Turns a mutable borrow into a single shared borrow.
+    pub fn downgrade(&self) {
+        self.n.store(1, Ordering::Release)
+    }
+}
+
+/// A flag, such as whether a `RootedRefCell` is poisoned.
+#[cfg(feature = "poison")]
+pub(crate) struct Flag {
+    #[cfg(not(feature = "atomic"))]
+    b: Cell<bool>,
+    #[cfg(feature = "atomic")]
+    b: AtomicBool,
+}
+
+#[cfg(all(feature = "poison", not(feature = "atomic")))]
+impl Flag {
+    pub fn new(b: bool) -> Self {
+        Self { b: Cell::new(b) }
+    }
+
+    pub fn get(&self) -> bool {
+        self.b.get()
+    }
+
+    pub fn set(&self, b: bool) {
+        self.b.set(b)
+    }
+}
+
+#[cfg(all(feature = "poison", feature = "atomic"))]
+impl Flag {
+    pub fn new(b: bool) -> Self {
+        Self {
+            b: AtomicBool::new(b),
+        }
+    }
+
+    pub fn get(&self) -> bool {
+        self.b.load(Ordering::Acquire)
+    }
+
+    pub fn set(&self, b: bool) {
+        self.b.store(b, Ordering::Release)
+    }
+}
+
+/// `once_cell::race::OnceBox`, reimplemented with loom's atomics so that loom
+/// can check the racy initialization of the tag seed. Threads that lose the
+/// race drop the value they initialized and use the winner's.
+#[cfg(loom)]
+pub(crate) struct OnceBox<T> {
+    ptr: loom::sync::atomic::AtomicPtr<T>,
+}
+
+#[cfg(loom)]
+unsafe impl<T: Send + Sync> Sync for OnceBox<T> {}
+
+#[cfg(loom)]
+impl<T> OnceBox<T> {
+    pub fn new() -> Self {
+        Self {
+            ptr: loom::sync::atomic::AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        let ptr = self.ptr.load(Ordering::Acquire);
+        // SAFETY: A non-null pointer was stored by `set`, and is only freed
+        // when `self` is dropped.
+        unsafe { ptr.as_ref() }
+    }
+
+    pub fn set(&self, value: alloc::boxed::Box<T>) -> Result<(), alloc::boxed::Box<T>> {
+        let ptr = alloc::boxed::Box::into_raw(value);
+        match self.ptr.compare_exchange(
+            core::ptr::null_mut(),
+            ptr,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => Ok(()),
+            // SAFETY: `ptr` came from `into_raw` above, and wasn't stored.
+            Err(_) => Err(unsafe { alloc::boxed::Box::from_raw(ptr) }),
+        }
+    }
+
+    pub fn get_or_init(&self, f: impl FnOnce() -> alloc::boxed::Box<T>) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        let _ = self.set(f());
+        self.get().unwrap()
+    }
+}
+
+#[cfg(loom)]
+impl<T> Drop for OnceBox<T> {
+    fn drop(&mut self) {
+        let ptr = self.ptr.with_mut(|ptr| *ptr);
+        if !ptr.is_null() {
+            // SAFETY: `ptr` was stored by `set`, and nothing else frees it.
+            drop(unsafe { alloc::boxed::Box::from_raw(ptr) });
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Internal instrumentation hooks.
+//!
+//! With the `tracing` feature enabled these forward to the corresponding
+//! `tracing` macros, so that a subscriber can correlate object lifetimes with
+//! the `Root` (via its tag) they belong to. Without the feature they expand to
+//! nothing, and their arguments are never evaluated.
+
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)*);
+    };
+}
+
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+macro_rules! error_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::error!($($arg)*);
+    };
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod test_trace {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Level, Metadata, Subscriber};
+
+    use crate::rc::RootedRc;
+    use crate::refcell::RootedRefCell;
+    use crate::Root;
+
+    /// Records the level and message of every event.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<(Level, String)>>>);
+
+    impl Recorder {
+        fn contains(&self, level: Level, message: &str) -> bool {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|(l, m)| *l == level && m == message)
+        }
+    }
+
+    struct MessageVisitor(String);
+
+    impl Visit for MessageVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn core::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut visitor = MessageVisitor(String::new());
+            event.record(&mut visitor);
+            self.0
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), visitor.0));
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn emits_events() {
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let root = Root::new();
+            let other = Root::new();
+            let rc = RootedRc::new(&root, RootedRefCell::new(&root, 0));
+            *rc.borrow_mut(&root) += 1;
+
+            assert!(catch_unwind(AssertUnwindSafe(|| rc.clone(&other))).is_err());
+            assert!(rc.try_borrow(&other).is_err());
+            rc.safely_drop(&root);
+        });
+
+        assert!(recorder.contains(Level::TRACE, "allocated RootedRc"));
+        assert!(recorder.contains(Level::TRACE, "mutably borrowed RootedRefCell"));
+        assert!(recorder.contains(Level::ERROR, "used RootedRc with wrong root"));
+        assert!(recorder.contains(Level::ERROR, "borrowed RootedRefCell with wrong root"));
+        assert!(recorder.contains(Level::TRACE, "freed RootedRc"));
+    }
+}
@@ -0,0 +1,16 @@
+//! Checks that misuses ruled out by the crate's safety argument fail to
+//! compile. Expected compiler output is in `tests/ui/*.stderr`; regenerate it
+//! with `TRYBUILD=overwrite cargo test --test compile_fail`.
+
+#[test]
+#[cfg_attr(miri, ignore)]
+// These add more `!Sync` fields to `Root`, changing which one the compiler
+// reports.
+#[cfg_attr(
+    any(feature = "stats", feature = "journal", feature = "leak_check"),
+    ignore = "expected output assumes default features"
+)]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,101 @@
+//! Model checks of the pattern of handing a `Root`, and the objects associated
+//! with it, between worker threads, and of racing the first `Root` against
+//! `set_tag_seed_source`. loom explores every interleaving, and reports an
+//! error if reference counts or borrow flags are ever accessed concurrently.
+//! The `TagAllocator` itself is modelled in `test_loom_tag` in `src/lib.rs`.
+//!
+//! Run with `maint/checks/loom.sh`.
+#![cfg(loom)]
+
+use loom::sync::{Arc, Mutex};
+use loom::thread;
+use objgraph::{rc::RootedRc, refcell::RootedRefCell, set_tag_seed_source, Root};
+
+#[test]
+fn send_to_worker_thread_and_retrieve() {
+    loom::model(|| {
+        let root = Root::new();
+        let rc = RootedRc::new(&root, RootedRefCell::new(&root, 0));
+        let rc_thread = rc.clone(&root);
+        let root = thread::spawn(move || {
+            *rc_thread.borrow_mut(&root) += 1;
+            rc_thread.safely_drop(&root);
+            root
+        })
+        .join()
+        .unwrap();
+        assert_eq!(*rc.borrow(&root), 1);
+        rc.safely_drop(&root);
+    });
+}
+
+#[test]
+fn threads_contend_over_lock() {
+    loom::model(|| {
+        let root = Arc::new(Mutex::new(Root::new()));
+        let rc = {
+            let root = root.lock().unwrap();
+            RootedRc::new(&root, RootedRefCell::new(&root, 0))
+        };
+
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let rc = rc.clone(&root.lock().unwrap());
+                let root = root.clone();
+                thread::spawn(move || {
+                    let root = root.lock().unwrap();
+                    let rc2 = rc.clone(&root);
+                    *rc2.borrow_mut(&root) += 1;
+                    rc.safely_drop(&root);
+                    rc2.safely_drop(&root);
+                })
+            })
+            .collect();
+
+        for handle in threads {
+            handle.join().unwrap();
+        }
+
+        let root = root.lock().unwrap();
+        assert_eq!(*rc.borrow(&root), 2);
+        rc.safely_drop(&root);
+    });
+}
+
+#[test]
+fn roots_created_concurrently_are_distinct() {
+    loom::model(|| {
+        let handle = thread::spawn(Root::new);
+        let root = Root::new();
+        let other = handle.join().unwrap();
+        let rc = RootedRc::new(&root, ());
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            rc.clone(&other).safely_drop(&other);
+        }))
+        .is_err());
+        rc.safely_drop(&root);
+    });
+}
+
+#[test]
+fn seed_source_races_first_root() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn source() -> u64 {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        0
+    }
+
+    loom::model(|| {
+        CALLS.store(0, Ordering::Relaxed);
+        let handle = thread::spawn(|| set_tag_seed_source(source));
+        Root::new();
+        let set = handle.join().unwrap();
+        // The source is used if and only if it was set before the first tag
+        // was allocated, and then only once.
+        assert_eq!(CALLS.load(Ordering::Relaxed), usize::from(set.is_ok()));
+        Root::new();
+        assert_eq!(CALLS.load(Ordering::Relaxed), usize::from(set.is_ok()));
+    });
+}
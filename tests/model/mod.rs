@@ -0,0 +1,254 @@
+//! A reference model of `RootedRc` and `RootedRefCell`, and a driver that runs
+//! sequences of operations against both the real types and the model, checking
+//! that they agree.
+//!
+//! Shared by `tests/proptest_model.rs` and the fuzz target in `fuzz/`.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use objgraph::refcell::{BorrowError, RootedRefCell};
+use objgraph::{rc::RootedRc, Root};
+
+/// An operation to apply. Indices are taken modulo the number of roots or
+/// objects currently live, so every sequence of operations is valid input.
+#[derive(Debug, Clone)]
+#[cfg_attr(fuzzing, derive(arbitrary::Arbitrary))]
+pub enum Op {
+    /// Create a new root.
+    NewRoot,
+    /// Allocate a new object associated with `root`.
+    New { root: u8 },
+    /// Clone object `obj`, using `root` as proof. Panics if `root` is the
+    /// wrong root.
+    Clone { obj: u8, root: u8 },
+    /// Safely drop object `obj`, using `root` as proof. Panics, leaking the
+    /// object, if `root` is the wrong root.
+    Drop { obj: u8, root: u8 },
+    /// Borrow each object in `borrows` in turn, mutably if the flag is set,
+    /// holding all of the resulting guards until the end of the operation.
+    Borrow { root: u8, borrows: Vec<(u8, bool)> },
+    /// Move all roots and objects to another thread, clone and drop object
+    /// `obj` there, and move everything back.
+    SendToThread { obj: u8 },
+}
+
+struct Payload {
+    value: u32,
+    freed: Arc<AtomicBool>,
+}
+
+impl Drop for Payload {
+    fn drop(&mut self) {
+        assert!(!self.freed.swap(true, Ordering::Relaxed), "double free");
+    }
+}
+
+type Obj = RootedRc<RootedRefCell<Payload>>;
+
+/// Expected state of a single allocation.
+struct ModelAlloc {
+    root: usize,
+    strong_count: usize,
+    value: u32,
+    leaked: bool,
+    freed: Arc<AtomicBool>,
+}
+
+struct Handle {
+    alloc: usize,
+    obj: Obj,
+}
+
+#[derive(Default)]
+struct State {
+    roots: Vec<Root>,
+    handles: Vec<Handle>,
+    allocs: Vec<ModelAlloc>,
+}
+
+/// Runs `ops`, panicking if the real types ever disagree with the model.
+///
+/// If `check_panics` is false, operations that are expected to panic are
+/// skipped instead of being run and checked. This is needed under fuzzers that
+/// treat any panic as a crash, even if it is caught.
+pub fn run(ops: &[Op], check_panics: bool) {
+    let mut state = State {
+        roots: vec![Root::new()],
+        ..State::default()
+    };
+    for op in ops {
+        state = state.apply(op, check_panics);
+    }
+    state.finish();
+}
+
+impl State {
+    fn root_index(&self, root: u8) -> usize {
+        root as usize % self.roots.len()
+    }
+
+    fn handle_index(&self, obj: u8) -> Option<usize> {
+        if self.handles.is_empty() {
+            None
+        } else {
+            Some(obj as usize % self.handles.len())
+        }
+    }
+
+    fn apply(mut self, op: &Op, check_panics: bool) -> Self {
+        match *op {
+            Op::NewRoot => self.roots.push(Root::new()),
+            Op::New { root } => {
+                let root = self.root_index(root);
+                let freed = Arc::new(AtomicBool::new(false));
+                let r = &self.roots[root];
+                let obj = RootedRc::new(
+                    r,
+                    RootedRefCell::new(
+                        r,
+                        Payload {
+                            value: 0,
+                            freed: freed.clone(),
+                        },
+                    ),
+                );
+                self.allocs.push(ModelAlloc {
+                    root,
+                    strong_count: 1,
+                    value: 0,
+                    leaked: false,
+                    freed,
+                });
+                self.handles.push(Handle {
+                    alloc: self.allocs.len() - 1,
+                    obj,
+                });
+            }
+            Op::Clone { obj, root } => {
+                let Some(h) = self.handle_index(obj) else {
+                    return self;
+                };
+                let root = self.root_index(root);
+                let alloc = self.handles[h].alloc;
+                if root == self.allocs[alloc].root {
+                    let obj = self.handles[h].obj.clone(&self.roots[root]);
+                    self.allocs[alloc].strong_count += 1;
+                    self.handles.push(Handle { alloc, obj });
+                } else if check_panics {
+                    let res = catch_unwind(AssertUnwindSafe(|| {
+                        self.handles[h].obj.clone(&self.roots[root])
+                    }));
+                    assert!(res.is_err(), "clone with wrong root didn't panic");
+                }
+            }
+            Op::Drop { obj, root } => {
+                let Some(h) = self.handle_index(obj) else {
+                    return self;
+                };
+                let root = self.root_index(root);
+                let alloc = self.handles[h].alloc;
+                if root == self.allocs[alloc].root {
+                    let handle = self.handles.swap_remove(h);
+                    handle.obj.safely_drop(&self.roots[root]);
+                    let model = &mut self.allocs[alloc];
+                    model.strong_count -= 1;
+                    assert_eq!(
+                        model.freed.load(Ordering::Relaxed),
+                        model.strong_count == 0 && !model.leaked
+                    );
+                } else if check_panics {
+                    let handle = self.handles.swap_remove(h);
+                    let r = &self.roots[root];
+                    let res = catch_unwind(AssertUnwindSafe(|| handle.obj.safely_drop(r)));
+                    assert!(res.is_err(), "drop with wrong root didn't panic");
+                    // The reference is leaked, so the allocation never will be
+                    // freed.
+                    self.allocs[alloc].leaked = true;
+                }
+            }
+            Op::Borrow { root, ref borrows } => {
+                let root = self.root_index(root);
+                let r = &self.roots[root];
+                // (readers, writer) for each allocation.
+                let mut model_borrows = vec![(0usize, false); self.allocs.len()];
+                let mut guards = Vec::new();
+                let mut guards_mut = Vec::new();
+                for &(obj, mutable) in borrows {
+                    let Some(h) = self.handle_index(obj) else {
+                        break;
+                    };
+                    let handle = &self.handles[h];
+                    let model = &mut model_borrows[handle.alloc];
+                    let expected = if self.allocs[handle.alloc].root != root {
+                        Err(BorrowError::WrongRoot)
+                    } else if model.1 {
+                        Err(BorrowError::MutablyBorrowed)
+                    } else if mutable && model.0 > 0 {
+                        Err(BorrowError::Borrowed)
+                    } else {
+                        Ok(())
+                    };
+                    if mutable {
+                        let res = handle.obj.try_borrow_mut(r);
+                        assert_eq!(res.as_ref().err().copied(), expected.err());
+                        if let Ok(mut guard) = res {
+                            guard.value += 1;
+                            self.allocs[handle.alloc].value += 1;
+                            model.1 = true;
+                            guards_mut.push(guard);
+                        }
+                    } else {
+                        let res = handle.obj.try_borrow(r);
+                        assert_eq!(res.as_ref().err().copied(), expected.err());
+                        if let Ok(guard) = res {
+                            assert_eq!(guard.value, self.allocs[handle.alloc].value);
+                            model.0 += 1;
+                            guards.push(guard);
+                        }
+                    }
+                }
+            }
+            Op::SendToThread { obj } => {
+                self = thread::spawn(move || {
+                    if let Some(h) = self.handle_index(obj) {
+                        let handle = &self.handles[h];
+                        let root = &self.roots[self.allocs[handle.alloc].root];
+                        handle.obj.clone(root).safely_drop(root);
+                    }
+                    self
+                })
+                .join()
+                .unwrap();
+            }
+        }
+        self
+    }
+
+    /// Drops all remaining handles, and checks that exactly the allocations
+    /// that weren't leaked have been freed.
+    fn finish(mut self) {
+        for handle in self.handles.drain(..) {
+            let root = self.allocs[handle.alloc].root;
+            handle.obj.safely_drop(&self.roots[root]);
+        }
+        for alloc in &self.allocs {
+            assert_eq!(alloc.freed.load(Ordering::Relaxed), !alloc.leaked);
+        }
+        // Roots are only free of live objects if none of their allocations
+        // were leaked. Those that aren't would panic on drop.
+        #[cfg(feature = "leak_check")]
+        for (i, root) in self.roots.drain(..).enumerate() {
+            let leaked = self.allocs.iter().any(|a| a.root == i && a.leaked);
+            match root.try_drop() {
+                Ok(()) => assert!(!leaked),
+                Err(root) => {
+                    assert!(leaked);
+                    std::mem::forget(root);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! Checks random sequences of operations on `RootedRc` and `RootedRefCell`
+//! against a reference model. See `model/mod.rs`.
+
+mod model;
+
+use model::Op;
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        1 => Just(Op::NewRoot),
+        4 => any::<u8>().prop_map(|root| Op::New { root }),
+        4 => (any::<u8>(), any::<u8>()).prop_map(|(obj, root)| Op::Clone { obj, root }),
+        4 => (any::<u8>(), any::<u8>()).prop_map(|(obj, root)| Op::Drop { obj, root }),
+        4 => (any::<u8>(), vec((any::<u8>(), any::<bool>()), 0..4))
+            .prop_map(|(root, borrows)| Op::Borrow { root, borrows }),
+        1 => any::<u8>().prop_map(|obj| Op::SendToThread { obj }),
+    ]
+}
+
+proptest! {
+    // Keep miri runs (see `maint/checks/miri.sh`) tractable. Miri's isolation
+    // also prevents persisting failures to disk.
+    #![proptest_config(if cfg!(miri) {
+        ProptestConfig { cases: 4, failure_persistence: None, ..ProptestConfig::default() }
+    } else {
+        ProptestConfig::default()
+    })]
+
+    #[test]
+    fn matches_model(ops in vec(op(), 0..64)) {
+        model::run(&ops, true);
+    }
+}
@@ -0,0 +1,10 @@
+// `get_mut_with` needs the root exclusively, so no guard can be outstanding.
+use objgraph::{refcell::RootedRefCell, Root};
+
+fn main() {
+    let mut root = Root::new();
+    let cell = RootedRefCell::new(&root, 0);
+    let guard = cell.borrow(&root);
+    *cell.get_mut_with(&mut root) += 1;
+    drop(guard);
+}
@@ -0,0 +1,10 @@
+// A mutable guard can't outlive the root it was borrowed with.
+use objgraph::{refcell::RootedRefCell, Root};
+
+fn main() {
+    let root = Root::new();
+    let cell = RootedRefCell::new(&root, 0);
+    let mut guard = cell.borrow_mut(&root);
+    drop(root);
+    *guard += 1;
+}
@@ -0,0 +1,14 @@
+// Guards can't be sent to another thread; otherwise that thread could release
+// the borrow while the thread holding the root borrows the cell again.
+use objgraph::{refcell::RootedRefCell, Root};
+
+fn main() {
+    let root = Root::new();
+    let cell = RootedRefCell::new(&root, 0);
+    let mut guard = cell.borrow_mut(&root);
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            *guard += 1;
+        });
+    });
+}
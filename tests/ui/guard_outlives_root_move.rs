@@ -0,0 +1,11 @@
+// A guard borrows the root, so the root can't be handed to another thread
+// while the guard is still outstanding.
+use objgraph::{refcell::RootedRefCell, Root};
+
+fn main() {
+    let root = Root::new();
+    let cell = RootedRefCell::new(&root, 0);
+    let guard = cell.borrow(&root);
+    std::thread::spawn(move || drop(root));
+    drop(guard);
+}
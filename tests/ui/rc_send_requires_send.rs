@@ -0,0 +1,14 @@
+// `RootedRc<T>` may drop `T` on whichever thread holds the root, so it can
+// only be sent to another thread if `T` is `Send`.
+use std::sync::{Mutex, MutexGuard};
+
+use objgraph::{rc::RootedRc, Root};
+
+fn main() {
+    static MUTEX: Mutex<i32> = Mutex::new(0);
+    let root = Root::new();
+    let rc: RootedRc<MutexGuard<'static, i32>> = RootedRc::new(&root, MUTEX.lock().unwrap());
+    std::thread::spawn(move || {
+        rc.safely_drop(&root);
+    });
+}
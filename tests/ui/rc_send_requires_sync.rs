@@ -0,0 +1,14 @@
+// `RootedRc<T>` hands out `&T` without the root, so it can only be sent to
+// another thread if `T` is `Sync`.
+use std::cell::Cell;
+
+use objgraph::{rc::RootedRc, Root};
+
+fn main() {
+    let root = Root::new();
+    let rc = RootedRc::new(&root, Cell::new(0));
+    std::thread::spawn(move || {
+        rc.set(1);
+        rc.safely_drop(&root);
+    });
+}
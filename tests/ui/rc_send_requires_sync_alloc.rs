@@ -0,0 +1,31 @@
+// Every clone of a `RootedRc` hands out `&A` through `allocator` without the
+// root, so it can only be sent to another thread if the allocator is `Sync`,
+// as well as `Send`.
+use std::alloc::Layout;
+use std::cell::Cell;
+use std::ptr::NonNull;
+
+use allocator_api2::alloc::{AllocError, Allocator, Global};
+use objgraph::{rc::RootedRc, Root};
+
+// `Send` but not `Sync`.
+struct CountingAlloc(Cell<usize>);
+
+unsafe impl Allocator for CountingAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.set(self.0.get() + 1);
+        Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { Global.deallocate(ptr, layout) }
+    }
+}
+
+fn main() {
+    let root = Root::new();
+    let rc = RootedRc::new_in(&root, 0, CountingAlloc(Cell::new(0)));
+    std::thread::spawn(move || {
+        rc.safely_drop(&root);
+    });
+}
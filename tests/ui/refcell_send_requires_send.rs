@@ -0,0 +1,12 @@
+// `RootedRefCell<T>` can only be sent to another thread if `T` is `Send`.
+use std::rc::Rc;
+
+use objgraph::{refcell::RootedRefCell, Root};
+
+fn main() {
+    let root = Root::new();
+    let cell = RootedRefCell::new(&root, Rc::new(0));
+    std::thread::spawn(move || {
+        let _ = cell.borrow(&root);
+    });
+}
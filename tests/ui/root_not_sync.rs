@@ -0,0 +1,11 @@
+// `Root` is `!Sync`, so a reference to it can't be shared with another thread.
+use objgraph::Root;
+
+fn main() {
+    let root = Root::new();
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let _ = &root;
+        });
+    });
+}